@@ -1,106 +1,1708 @@
-use std::num::ParseIntError;
+use std::{
+    collections::HashMap,
+    fmt,
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
-#[allow(unused)]
+use crate::{
+    instruction::{self, Instruction, Instruction::*},
+    memory::{MEMORY_SIZE, PROGRAM_START},
+    symbols::SymbolTable,
+};
+
+// Labels and `equ` constants
+type Symbols = HashMap<String, u16>;
+
+// Largest value that fits in each kind of operand
+const NIBBLE: u16 = 0xF;
+const BYTE: u16 = 0xFF;
+const ADDR: u16 = 0xFFF;
+const WORD: u16 = 0xFFFF;
+
+/// Names of the mnemonic dialects, for `Syntax::from_name`.
+pub const SYNTAXES: &[&str] = &["native", "cowgod"];
+
+/// The mnemonics of the instructions. Directives, macros and control flow are the same in
+/// every dialect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// The mnemonics in `docs/assembly_lang.md`, like `mov v0 5`.
+    #[default]
+    Native,
+    /// The mnemonics of Cowgod's Chip-8 technical reference, like `LD V0, 5`.
+    Cowgod,
+}
+
+impl Syntax {
+    /// Looks up a dialect by the names in `SYNTAXES`.
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name {
+            "native" => Some(Syntax::Native),
+            "cowgod" => Some(Syntax::Cowgod),
+            _ => None,
+        }
+    }
+
+    // The mnemonic of a native instruction in this dialect, for generated statements
+    fn mnemonic(self, native: &'static str) -> &'static str {
+        match (self, native) {
+            (Syntax::Cowgod, "skeq") => "se",
+            (Syntax::Cowgod, "skne") => "sne",
+            (Syntax::Cowgod, "jmp") => "jp",
+            (Syntax::Cowgod, "jsr") => "call",
+            _ => native,
+        }
+    }
+}
+
+/// An error in the assembly source, pointing at the token that caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    /// 1-based
+    pub line: usize,
+    /// 1-based, counted in characters
+    pub column: usize,
+    /// Number of characters to underline
+    pub len: usize,
+    pub message: String,
+    /// The whole line the error is in, to show it in context
+    pub source_line: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Keep tabs so the caret lines up with the source line
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, self.file, self.line, self.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source_line)?;
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(self.len.max(1)))
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    /// 1-based, counted in characters
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+    file: Rc<str>,
+    line: usize,
+    source_line: Rc<str>,
+    label: Option<Token>,
+    /// Whether the label was written in the source, rather than made up by a macro or a block,
+    /// so it goes in the symbol table
+    exported: bool,
+    /// The mnemonic followed by its operands, empty if the statement is only a label
+    tokens: Vec<Token>,
+}
+
+/// An assembled program, with the outputs that describe it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    pub code: Vec<u8>,
+    /// The address of every label
+    pub symbols: SymbolTable,
+    /// The address, bytes and source of every line
+    pub listing: String,
+}
+
+struct ListingRow {
+    file: Rc<str>,
+    line: usize,
+    addr: usize,
+    bytes: Vec<u8>,
+    source_line: Rc<str>,
+}
+
+/// Assembles `source`, reporting errors as coming from `<input>`.
+pub fn assemble(source: &str) -> Result<Vec<u8>, Vec<AsmError>> {
+    assemble_named("<input>", source).map(|assembly| assembly.code)
+}
+
+/// Assembles `source`, reporting errors as coming from `file`. Files included with `include`
+/// and `incbin` are relative to `file`.
+/// All the errors in the source are returned, not only the first one.
+pub fn assemble_named(file: &str, source: &str) -> Result<Assembly, Vec<AsmError>> {
+    assemble_with_syntax(file, source, Syntax::Native)
+}
+
+/// Same as `assemble_named`, with the mnemonics of `syntax`.
+pub fn assemble_with_syntax(
+    file: &str,
+    source: &str,
+    syntax: Syntax,
+) -> Result<Assembly, Vec<AsmError>> {
+    let mut errors = Vec::new();
+    let mut includes = Path::new(file).canonicalize().into_iter().collect();
+    let statements = parse_file(file, source, &mut includes, &mut errors);
+    let statements = Expander {
+        macros: HashMap::new(),
+        constants: Symbols::new(),
+        expansions: 0,
+        statements: 0,
+        errors: &mut errors,
+    }
+    .expand(statements, 0);
+    let statements = lower_control_flow(statements, syntax, &mut errors);
+
+    // The first pass only finds where statements and labels are, so labels can be used before
+    // being defined. Constants have to be defined before they are used.
+    let mut symbols = Symbols::new();
+    let mut labels = SymbolTable::new();
+    let mut addrs = Vec::new();
+    let mut addr = PROGRAM_START;
+    for statement in &statements {
+        if let Some(label) = &statement.label {
+            statement.define(&mut symbols, label, addr as u16, &mut errors);
+            if statement.exported {
+                labels.insert(addr as u16, &label.text);
+            }
+        }
+        if statement.is_equ() {
+            match statement.value(2, &symbols, WORD) {
+                Ok(value) => {
+                    statement.define(&mut symbols, &statement.tokens[0], value, &mut errors)
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        match next_addr(statement, addr, syntax, &symbols) {
+            Ok(next) => {
+                addrs.push(Some(addr));
+                addr = next;
+            }
+            Err(err) => {
+                errors.push(err);
+                addrs.push(None);
+            }
+        }
+    }
+    let end = addr;
 
-pub fn assemble(assembly_code: &str) -> Vec<u8> {
-    let assembly_code = assembly_code.replace(";", "\n");
     let mut machine_code = Vec::new();
+    let mut listing: Vec<ListingRow> = Vec::new();
+    for (statement, addr) in statements.iter().zip(addrs) {
+        let Some(addr) = addr else { continue };
+
+        // org and align only move the address, the gap is filled here
+        machine_code.resize(addr - PROGRAM_START, 0);
+        if !statement.tokens.is_empty() && !statement.is_equ() {
+            if let Err(err) = assemble_statement(statement, &symbols, syntax, &mut machine_code) {
+                errors.push(err);
+            }
+        }
+
+        let bytes = &machine_code[addr - PROGRAM_START..];
+        match listing.last_mut() {
+            Some(row) if row.file == statement.file && row.line == statement.line => {
+                row.bytes.extend(bytes)
+            }
+            _ => listing.push(ListingRow {
+                file: statement.file.clone(),
+                line: statement.line,
+                addr,
+                bytes: bytes.to_vec(),
+                source_line: statement.source_line.clone(),
+            }),
+        }
+    }
+    machine_code.resize(end - PROGRAM_START, 0);
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let listing = listing
+        .iter()
+        .map(|row| {
+            let bytes: Vec<_> = row.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!(
+                "{:04x}  {:<16} {}\n",
+                row.addr,
+                bytes.join(" "),
+                row.source_line
+            )
+        })
+        .collect();
+    Ok(Assembly {
+        code: machine_code,
+        symbols: labels,
+        listing,
+    })
+}
+
+// Splits the source into statements, replacing include statements by the statements of the
+// included file. `includes` are the files being included, to find cycles.
+fn parse_file(
+    file: &str,
+    source: &str,
+    includes: &mut Vec<PathBuf>,
+    errors: &mut Vec<AsmError>,
+) -> Vec<Statement> {
+    let file: Rc<str> = file.into();
+    let mut statements = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let source_line: Rc<str> = line.into();
+        for tokens in tokenize(line) {
+            let (label, tokens) = split_label(tokens);
+            let statement = Statement {
+                file: file.clone(),
+                line: i + 1,
+                source_line: source_line.clone(),
+                label,
+                exported: true,
+                tokens,
+            };
 
-    for (i, line) in assembly_code.lines().enumerate() {
-        let line = line.trim();
-        let tokens: Vec<&str> = line.split(' ').filter(|&t| !t.is_empty()).collect();
+            if statement.keyword().as_deref() != Some("include") {
+                statements.push(statement);
+                continue;
+            }
+            if statement.label.is_some() {
+                statements.push(statement.label_only());
+            }
+            match statement.include(includes, errors) {
+                Ok(included) => statements.extend(included),
+                Err(err) => errors.push(err),
+            }
+        }
+    }
+    statements
+}
+
+fn assemble_statement(
+    statement: &Statement,
+    symbols: &Symbols,
+    syntax: Syntax,
+    machine_code: &mut Vec<u8>,
+) -> Result<(), AsmError> {
+    let s = statement;
+    let num = |n, max| s.value(n, symbols, max);
+
+    let mnemonic = s.keyword().unwrap();
+    match mnemonic.as_str() {
+        "sys" => {
+            // 0nnn, a machine language routine of the original interpreters
+            machine_code.extend(s.value(1, symbols, ADDR)?.to_be_bytes());
+            return Ok(());
+        }
+        "db" => {
+            for n in 1..s.tokens.len() {
+                machine_code.push(num(n, BYTE)? as u8);
+            }
+            return Ok(());
+        }
+        "dw" => {
+            for n in 1..s.tokens.len() {
+                machine_code.extend(num(n, WORD)?.to_be_bytes());
+            }
+            return Ok(());
+        }
+        "org" | "align" => return Ok(()),
+        "incbin" => {
+            machine_code.extend(s.read_incbin()?);
+            return Ok(());
+        }
+        "mvil" if syntax == Syntax::Native => {
+            // F000 nnnn, the address takes a second word
+            let long_addr = num(1, WORD)?;
+            machine_code.extend(instruction::encode(&SetILong).to_be_bytes());
+            machine_code.extend(long_addr.to_be_bytes());
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let instruction = match syntax {
+        Syntax::Native => native_instruction(s, &mnemonic, symbols)?,
+        Syntax::Cowgod => cowgod_instruction(s, &mnemonic, symbols)?,
+    };
+    machine_code.extend(instruction::encode(&instruction).to_be_bytes());
+    Ok(())
+}
 
-        if tokens.is_empty() {
+fn native_instruction(
+    s: &Statement,
+    mnemonic: &str,
+    symbols: &Symbols,
+) -> Result<Instruction, AsmError> {
+    let reg = |n| s.reg(n);
+    let num = |n, max| s.value(n, symbols, max);
+    let addr = |n| s.value(n, symbols, ADDR);
+
+    let instruction = match mnemonic {
+        "cls" => Clear,
+        "rts" => Return,
+        "scd" => ScrollDown(num(1, NIBBLE)? as u8),
+        "scr" => ScrollRight,
+        "scl" => ScrollLeft,
+        "exit" => Exit,
+        "low" => Lores,
+        "high" => Hires,
+        "jmp" => Jump(addr(1)?),
+        "jsr" => Call(addr(1)?),
+        "skeq" => s.xnn_or_xy(symbols, SkipEqImm, SkipEqReg)?,
+        "skne" => s.xnn_or_xy(symbols, SkipNeqImm, SkipNeqReg)?,
+        "mov" => s.xnn_or_xy(symbols, SetImm, Move)?,
+        "add" => s.xnn_or_xy(symbols, AddImm, Add)?,
+        "or" => Or(reg(1)?, reg(2)?),
+        "and" => And(reg(1)?, reg(2)?),
+        "xor" => Xor(reg(1)?, reg(2)?),
+        "sub" => Sub(reg(1)?, reg(2)?),
+        "shr" => s.x_or_xy(ShiftRight)?,
+        "rsb" => SubReverse(reg(1)?, reg(2)?),
+        "shl" => s.x_or_xy(ShiftLeft)?,
+        "mvi" => SetI(addr(1)?),
+        "jmi" => JumpOffset(addr(1)?),
+        "rand" => Rand(reg(1)?, num(2, BYTE)? as u8),
+        "sprite" => Draw(reg(1)?, reg(2)?, num(3, NIBBLE)? as u8),
+        "xsprite" => Draw(reg(1)?, reg(2)?, 0),
+        "skpr" => SkipKey(reg(1)?),
+        "skup" => SkipNotKey(reg(1)?),
+        "gdelay" => GetDelay(reg(1)?),
+        "key" => WaitKey(reg(1)?),
+        "sdelay" => SetDelay(reg(1)?),
+        "ssound" => SetSound(reg(1)?),
+        "adi" => AddI(reg(1)?),
+        "font" => Font(reg(1)?),
+        "xfont" => BigFont(reg(1)?),
+        "bcd" => Bcd(reg(1)?),
+        "str" => Store(reg(1)?),
+        "ldr" => Load(reg(1)?),
+        "strr" => StoreRange(reg(1)?, reg(2)?),
+        "ldrr" => LoadRange(reg(1)?, reg(2)?),
+        "plane" => Plane(num(1, NIBBLE)? as u8),
+        "audio" => Audio,
+        "pitch" => Pitch(reg(1)?),
+        "sflags" => StoreFlags(reg(1)?),
+        "gflags" => LoadFlags(reg(1)?),
+
+        "end" => End,
+        _ => return Err(unknown_instruction(s)),
+    };
+    Ok(instruction)
+}
+
+// The mnemonics of Cowgod's Chip-8 technical reference, with the SUPER-CHIP additions. Operands
+// like `I`, `DT` or `[I]` pick the instruction, as in `LD Vx, DT` and `LD DT, Vx`.
+fn cowgod_instruction(
+    s: &Statement,
+    mnemonic: &str,
+    symbols: &Symbols,
+) -> Result<Instruction, AsmError> {
+    let reg = |n| s.reg(n);
+    let num = |n, max| s.value(n, symbols, max);
+    let addr = |n| s.value(n, symbols, ADDR);
+
+    let operands: Vec<String> = s.tokens[1..]
+        .iter()
+        .map(|t| t.text.to_ascii_lowercase())
+        .collect();
+    let operands: Vec<&str> = operands.iter().map(String::as_str).collect();
+    let instruction = match (mnemonic, operands.as_slice()) {
+        ("cls", []) => Clear,
+        ("ret", []) => Return,
+        ("scd", [_]) => ScrollDown(num(1, NIBBLE)? as u8),
+        ("scr", []) => ScrollRight,
+        ("scl", []) => ScrollLeft,
+        ("exit", []) => Exit,
+        ("low", []) => Lores,
+        ("high", []) => Hires,
+        ("jp", ["v0", _]) => JumpOffset(addr(2)?),
+        ("jp", [_]) => Jump(addr(1)?),
+        ("call", [_]) => Call(addr(1)?),
+        ("se", [_, _]) => s.xnn_or_xy(symbols, SkipEqImm, SkipEqReg)?,
+        ("sne", [_, _]) => s.xnn_or_xy(symbols, SkipNeqImm, SkipNeqReg)?,
+        ("ld", ["i", _]) => SetI(addr(2)?),
+        ("ld", ["dt", _]) => SetDelay(reg(2)?),
+        ("ld", ["st", _]) => SetSound(reg(2)?),
+        ("ld", ["f", _]) => Font(reg(2)?),
+        ("ld", ["hf", _]) => BigFont(reg(2)?),
+        ("ld", ["b", _]) => Bcd(reg(2)?),
+        ("ld", ["[i]", _]) => Store(reg(2)?),
+        ("ld", ["r", _]) => StoreFlags(reg(2)?),
+        ("ld", [_, "dt"]) => GetDelay(reg(1)?),
+        ("ld", [_, "k"]) => WaitKey(reg(1)?),
+        ("ld", [_, "[i]"]) => Load(reg(1)?),
+        ("ld", [_, "r"]) => LoadFlags(reg(1)?),
+        ("ld", [_, _]) => s.xnn_or_xy(symbols, SetImm, Move)?,
+        ("add", ["i", _]) => AddI(reg(2)?),
+        ("add", [_, _]) => s.xnn_or_xy(symbols, AddImm, Add)?,
+        ("or", [_, _]) => Or(reg(1)?, reg(2)?),
+        ("and", [_, _]) => And(reg(1)?, reg(2)?),
+        ("xor", [_, _]) => Xor(reg(1)?, reg(2)?),
+        ("sub", [_, _]) => Sub(reg(1)?, reg(2)?),
+        ("subn", [_, _]) => SubReverse(reg(1)?, reg(2)?),
+        ("shr", [_] | [_, _]) => s.x_or_xy(ShiftRight)?,
+        ("shl", [_] | [_, _]) => s.x_or_xy(ShiftLeft)?,
+        ("rnd", [_, _]) => Rand(reg(1)?, num(2, BYTE)? as u8),
+        ("drw", [_, _, _]) => Draw(reg(1)?, reg(2)?, num(3, NIBBLE)? as u8),
+        ("skp", [_]) => SkipKey(reg(1)?),
+        ("sknp", [_]) => SkipNotKey(reg(1)?),
+        _ if COWGOD_MNEMONICS.contains(&mnemonic) => {
+            let message = format!("Invalid operands for `{}`", s.tokens[0].text);
+            return Err(s.error(&s.tokens[0], message));
+        }
+        _ => return Err(unknown_instruction(s)),
+    };
+    Ok(instruction)
+}
+
+const COWGOD_MNEMONICS: [&str; 25] = [
+    "cls", "ret", "scd", "scr", "scl", "exit", "low", "high", "jp", "call", "se", "sne", "ld",
+    "add", "or", "and", "xor", "sub", "subn", "shr", "shl", "rnd", "drw", "skp", "sknp",
+];
+
+fn unknown_instruction(s: &Statement) -> AsmError {
+    let mnemonic = &s.tokens[0];
+    s.error(mnemonic, format!("Unknown instruction `{}`", mnemonic.text))
+}
+
+// Splits a line into statements, separated by `;`, and those into tokens, separated by spaces,
+// tabs or commas. Comments start with `#` or `//`. Quotes, parentheses and spaces next to an
+// operator don't split tokens, so `"a file"` and `(a + 1) * 2` are a single token.
+fn tokenize(line: &str) -> Vec<Vec<Token>> {
+    let mut statements = vec![Vec::new()];
+    let mut start = None;
+    let mut quote = None;
+    let mut depth = 0;
+
+    for (pos, c) in line.char_indices().chain([(line.len(), '\n')]) {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            if c != '\n' {
+                continue;
+            }
+        } else if c == '"' || c == '\'' {
+            quote = Some(c);
+            start = start.or(Some(pos));
             continue;
         }
 
-        let code = match tokens[0] {
-            "cls" => 0x00E0,
-            "rts" => 0x00EE,
-            "jmp" => 0x1000 | parse_num_or_err(tokens[1], i),
-            "jsr" => 0x2000 | parse_num_or_err(tokens[1], i),
-            "skeq" => parse_xnn_or_xy(tokens, i, 0x3000, 0x5000),
-            "skne" => parse_xnn_or_xy(tokens, i, 0x4000, 0x9000),
-            "mov" => parse_xnn_or_xy(tokens, i, 0x6000, 0x8000),
-            "add" => parse_xnn_or_xy(tokens, i, 0x7000, 0x8004),
-            "or" => 0x8001 | parse_xy(tokens, i),
-            "and" => 0x8002 | parse_xy(tokens, i),
-            "xor" => 0x8003 | parse_xy(tokens, i),
-            "sub" => 0x8005 | parse_xy(tokens, i),
-            "shr" => 0x8006 | parse_reg_or_err(tokens[1], i),
-            "rsb" => 0x8007 | parse_xy(tokens, i),
-            "shl" => 0x800E | parse_reg_or_err(tokens[1], i),
-            "mvi" => 0xA000 | parse_num_or_err(tokens[1], i),
-            "jmi" => 0xB000 | parse_num_or_err(tokens[1], i),
-            "rand" => 0xC000 | parse_xnn(tokens, i),
-            "sprite" => 0xD000 | parse_xyn(tokens, i),
-            "skpr" => 0xE09E | parse_reg_or_err(tokens[1], i) << 8,
-            "skup" => 0xE0A1 | parse_reg_or_err(tokens[1], i) << 8,
-            "gdelay" => 0xF007 | parse_reg_or_err(tokens[1], i) << 8,
-            "key" => 0xF00A | parse_reg_or_err(tokens[1], i) << 8,
-            "sdelay" => 0xF015 | parse_reg_or_err(tokens[1], i) << 8,
-            "ssound" => 0xF018 | parse_reg_or_err(tokens[1], i) << 8,
-            "adi" => 0xF01E | parse_reg_or_err(tokens[1], i) << 8,
-            "font" => 0xF029 | parse_reg_or_err(tokens[1], i) << 8,
-            "bcd" => 0xF033 | parse_reg_or_err(tokens[1], i) << 8,
-            "str" => 0xF055 | parse_reg_or_err(tokens[1], i) << 8,
-            "ldr" => 0xF065 | parse_reg_or_err(tokens[1], i) << 8,
-
-            "end" => 0x0000,
-            _ => {
-                panic!("Unknown instruction: {}", tokens[0]);
+        let comment = c == '#' || line[pos..].starts_with("//");
+        // Spaces around a binary operator stay inside the expression, but `-1` after a space is a
+        // new operand
+        let joined = |start: usize| {
+            let next = line[pos..].trim_start();
+            let negative = next.starts_with('-') && !next[1..].starts_with(char::is_whitespace);
+            depth > 0
+                || line[start..pos].trim_end().ends_with(OPERATOR_CHARS)
+                || (next.starts_with(OPERATOR_CHARS) && !next.starts_with("//") && !negative)
+        };
+        let separator = match c {
+            ';' | ',' | '\n' => true,
+            _ if comment => true,
+            _ if c.is_whitespace() => start.is_some_and(|start| !joined(start)),
+            _ => false,
+        };
+
+        if separator {
+            if let Some(start) = start.take() {
+                statements.last_mut().unwrap().push(Token {
+                    text: line[start..pos].trim_end().to_string(),
+                    column: line[..start].chars().count() + 1,
+                });
+            }
+            depth = 0;
+        } else if !c.is_whitespace() {
+            start = start.or(Some(pos));
+            match c {
+                '(' => depth += 1,
+                ')' => depth = (depth - 1).max(0),
+                _ => {}
+            }
+        }
+
+        if c == ';' {
+            statements.push(Vec::new());
+        }
+        if c == '\n' || comment {
+            break;
+        }
+    }
+
+    statements.retain(|tokens| !tokens.is_empty());
+    statements
+}
+
+// `name: instruction` -> (Some(name), instruction)
+fn split_label(mut tokens: Vec<Token>) -> (Option<Token>, Vec<Token>) {
+    if !tokens[0].text.ends_with(':') {
+        return (None, tokens);
+    }
+    let mut label = tokens.remove(0);
+    label.text.pop();
+    (Some(label), tokens)
+}
+
+// Deepest nesting of macro calls and rept blocks, so recursive macros stop
+const MAX_EXPANSION_DEPTH: usize = 64;
+// Most statements macros and rept blocks expand to, so macros that call each other more than
+// once don't take forever
+const MAX_EXPANDED_STATEMENTS: usize = 100_000;
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Statement>,
+}
+
+// Replaces macro definitions, macro calls and rept blocks by the statements they expand to
+struct Expander<'e> {
+    macros: HashMap<String, Macro>,
+    // equ constants seen so far, for rept counts
+    constants: Symbols,
+    // Number of blocks expanded so far, to give their local labels unique names
+    expansions: usize,
+    // Number of statements in them
+    statements: usize,
+    errors: &'e mut Vec<AsmError>,
+}
+
+impl Expander<'_> {
+    fn expand(&mut self, statements: Vec<Statement>, depth: usize) -> Vec<Statement> {
+        let mut expanded = Vec::new();
+        let mut statements = statements.into_iter();
+
+        while let Some(statement) = statements.next() {
+            let keyword = statement.keyword();
+            if matches!(keyword.as_deref(), Some("macro" | "rept")) {
+                let Some(body) = self.block(&statement, &mut statements) else {
+                    continue;
+                };
+                if statement.label.is_some() {
+                    expanded.push(statement.label_only());
+                }
+                if keyword.as_deref() == Some("macro") {
+                    self.define_macro(&statement, body);
+                } else {
+                    expanded.extend(self.rept(&statement, &body, depth));
+                }
+            } else if let Some(end @ ("endm" | "endr")) = keyword.as_deref() {
+                let start = if end == "endm" { "macro" } else { "rept" };
+                self.errors.push(statement.error(
+                    &statement.tokens[0],
+                    format!("`{}` without a `{}`", end, start),
+                ));
+            } else if let Some(m) = statement
+                .tokens
+                .first()
+                .and_then(|name| self.macros.get(&name.text))
+            {
+                let m = m.clone();
+                if statement.label.is_some() {
+                    expanded.push(statement.label_only());
+                }
+                expanded.extend(self.call(&statement, &m, depth));
+            } else {
+                if statement.is_equ() {
+                    if let Ok(value) = statement.value(2, &self.constants, WORD) {
+                        self.constants
+                            .insert(statement.tokens[0].text.clone(), value);
+                    }
+                }
+                expanded.push(statement);
             }
+        }
+        expanded
+    }
+
+    // Statements up to the endm or endr that closes the block opened by `start`
+    fn block(
+        &mut self,
+        start: &Statement,
+        statements: &mut impl Iterator<Item = Statement>,
+    ) -> Option<Vec<Statement>> {
+        let expected = match start.keyword().as_deref() {
+            Some("macro") => "endm",
+            _ => "endr",
+        };
+        let mut body = Vec::new();
+        let mut depth = 0;
+
+        for statement in statements.by_ref() {
+            match statement.keyword().as_deref() {
+                Some("macro" | "rept") => depth += 1,
+                Some(end @ ("endm" | "endr")) if depth == 0 => {
+                    if end != expected {
+                        self.errors.push(
+                            statement
+                                .error(&statement.tokens[0], format!("Expected `{}`", expected)),
+                        );
+                    }
+                    return Some(body);
+                }
+                Some("endm" | "endr") => depth -= 1,
+                _ => {}
+            }
+            body.push(statement);
+        }
+
+        self.errors.push(start.error(
+            &start.tokens[0],
+            format!("`{}` without an `{}`", start.tokens[0].text, expected),
+        ));
+        None
+    }
+
+    // macro name param1 param2 ...
+    fn define_macro(&mut self, statement: &Statement, body: Vec<Statement>) {
+        let name = match statement.operand(1) {
+            Ok(name) => name,
+            Err(err) => return self.errors.push(err),
+        };
+        let m = Macro {
+            params: statement.tokens[2..]
+                .iter()
+                .map(|t| t.text.clone())
+                .collect(),
+            body,
         };
+        if self.macros.insert(name.text.clone(), m).is_some() {
+            self.errors
+                .push(statement.error(name, format!("Macro `{}` is already defined", name.text)));
+        }
+    }
+
+    fn call(&mut self, statement: &Statement, m: &Macro, depth: usize) -> Vec<Statement> {
+        let name = &statement.tokens[0];
+        let args = &statement.tokens[1..];
+        if args.len() != m.params.len() {
+            self.errors.push(statement.error(
+                name,
+                format!(
+                    "`{}` expects {} arguments, got {}",
+                    name.text,
+                    m.params.len(),
+                    args.len()
+                ),
+            ));
+            return Vec::new();
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.errors
+                .push(statement.error(name, "Macros are nested too deeply"));
+            return Vec::new();
+        }
+        if !self.count(statement, &m.body) {
+            return Vec::new();
+        }
+
+        let args = m
+            .params
+            .iter()
+            .cloned()
+            .zip(args.iter().map(|t| t.text.clone()))
+            .collect();
+        let body = self.instantiate(&m.body, args);
+        self.expand(body, depth + 1)
+    }
+
+    // rept count
+    fn rept(&mut self, statement: &Statement, body: &[Statement], depth: usize) -> Vec<Statement> {
+        let count = match statement.value(1, &self.constants, WORD) {
+            Ok(count) => count,
+            Err(err) => {
+                self.errors.push(err);
+                return Vec::new();
+            }
+        };
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.errors
+                .push(statement.error(&statement.tokens[0], "Blocks are nested too deeply"));
+            return Vec::new();
+        }
+
+        let mut expanded = Vec::new();
+        for _ in 0..count {
+            if !self.count(statement, body) {
+                break;
+            }
+            let body = self.instantiate(body, HashMap::new());
+            expanded.extend(self.expand(body, depth + 1));
+        }
+        expanded
+    }
+
+    // Adds a copy of `body` to the statements expanded, false once there are too many. Only the
+    // first expansion past the limit reports it.
+    fn count(&mut self, statement: &Statement, body: &[Statement]) -> bool {
+        if self.statements > MAX_EXPANDED_STATEMENTS {
+            return false;
+        }
+        // Empty bodies count too, or nested rept blocks of them could run for a long time
+        self.statements += body.len().max(1);
+        if self.statements > MAX_EXPANDED_STATEMENTS {
+            self.errors.push(statement.error(
+                &statement.tokens[0],
+                "Macros and blocks expand to too many statements",
+            ));
+            return false;
+        }
+        true
+    }
+
+    // A copy of `body` with the arguments in place of the parameters, and its labels renamed so
+    // each copy has its own
+    fn instantiate(
+        &mut self,
+        body: &[Statement],
+        mut words: HashMap<String, String>,
+    ) -> Vec<Statement> {
+        self.expansions += 1;
+        for label in body.iter().filter_map(|s| s.label.as_ref()) {
+            let local = format!("{}.{}", label.text, self.expansions);
+            words.insert(label.text.clone(), local);
+        }
+
+        body.iter()
+            .map(|statement| {
+                let mut statement = statement.clone();
+                statement.exported &= statement.label.is_none();
+                for token in statement.label.iter_mut().chain(&mut statement.tokens) {
+                    token.text = substitute(&token.text, &words);
+                }
+                statement
+            })
+            .collect()
+    }
+}
+
+enum Block {
+    If { id: usize, has_else: bool },
+    // `loop` and `while`
+    Loop { id: usize },
+}
 
-        machine_code.push(((code & 0xFF00) >> 8) as u8);
-        machine_code.push((code & 0x00FF) as u8);
+// Lowers if/else, while, loop/break and call to skips, jumps and generated labels
+fn lower_control_flow(
+    statements: Vec<Statement>,
+    syntax: Syntax,
+    errors: &mut Vec<AsmError>,
+) -> Vec<Statement> {
+    let mut lowered = Vec::new();
+    let mut blocks: Vec<(Block, Statement)> = Vec::new();
+    let mut next_id = 0;
+
+    for statement in statements {
+        let keyword = statement.keyword();
+        if !matches!(
+            keyword.as_deref(),
+            Some("if" | "while" | "loop" | "break" | "call" | "}")
+        ) {
+            lowered.push(statement);
+            continue;
+        }
+        if statement.label.is_some() {
+            lowered.push(statement.label_only());
+        }
+
+        let first = &statement.tokens[0];
+        let texts: Vec<&str> = statement.tokens.iter().map(|t| t.text.as_str()).collect();
+        match keyword.as_deref().unwrap() {
+            "if" => {
+                next_id += 1;
+                match statement.condition_skip(syntax) {
+                    Ok(skip) => {
+                        lowered.push(skip);
+                        lowered.push(statement.jump(syntax, format!("_if{}.else", next_id)));
+                    }
+                    Err(err) => errors.push(err),
+                }
+                let block = Block::If {
+                    id: next_id,
+                    has_else: false,
+                };
+                blocks.push((block, statement));
+            }
+            "while" | "loop" => {
+                next_id += 1;
+                lowered.push(statement.generated(Some(format!("_loop{}", next_id)), Vec::new()));
+                if texts[0].eq_ignore_ascii_case("while") {
+                    match statement.condition_skip(syntax) {
+                        Ok(skip) => {
+                            lowered.push(skip);
+                            lowered.push(statement.jump(syntax, format!("_loop{}.end", next_id)));
+                        }
+                        Err(err) => errors.push(err),
+                    }
+                } else if texts != [texts[0], "{"] {
+                    errors.push(statement.error(first, "Expected `loop {`"));
+                }
+                blocks.push((Block::Loop { id: next_id }, statement));
+            }
+            "break" => {
+                let innermost = blocks.iter().rev().find_map(|(block, _)| match block {
+                    Block::Loop { id } => Some(*id),
+                    _ => None,
+                });
+                match innermost {
+                    Some(id) => lowered.push(statement.jump(syntax, format!("_loop{}.end", id))),
+                    None => errors.push(statement.error(first, "`break` outside of a loop")),
+                }
+            }
+            "call" => {
+                let mut tokens = statement.tokens.clone();
+                tokens[0].text = syntax.mnemonic("jsr").to_string();
+                lowered.push(statement.generated(None, tokens));
+            }
+            _ => match (texts.as_slice(), blocks.pop()) {
+                (["}"], Some((Block::If { id, has_else }, _))) => {
+                    let end = if has_else { "end" } else { "else" };
+                    let label = format!("_if{}.{}", id, end);
+                    lowered.push(statement.generated(Some(label), Vec::new()));
+                }
+                (["}"], Some((Block::Loop { id }, _))) => {
+                    lowered.push(statement.jump(syntax, format!("_loop{}", id)));
+                    let label = format!("_loop{}.end", id);
+                    lowered.push(statement.generated(Some(label), Vec::new()));
+                }
+                (
+                    ["}", "else", "{"],
+                    Some((
+                        Block::If {
+                            id,
+                            has_else: false,
+                        },
+                        start,
+                    )),
+                ) => {
+                    lowered.push(statement.jump(syntax, format!("_if{}.end", id)));
+                    let label = format!("_if{}.else", id);
+                    lowered.push(statement.generated(Some(label), Vec::new()));
+                    blocks.push((Block::If { id, has_else: true }, start));
+                }
+                (["}"] | ["}", "else", "{"], None) => {
+                    errors.push(statement.error(first, "`}` without a block to close"));
+                }
+                (_, block) => {
+                    blocks.extend(block);
+                    errors.push(statement.error(first, "Expected `}` or `} else {`"));
+                }
+            },
+        }
     }
 
-    machine_code
+    for (_, statement) in blocks {
+        let message = format!("`{}` without a closing `}}`", statement.tokens[0].text);
+        errors.push(statement.error(&statement.tokens[0], message));
+    }
+    lowered
 }
 
-fn parse_xyn(tokens: Vec<&str>, line: usize) -> u16 {
-    let reg1 = parse_reg_or_err(tokens[1], line);
-    let reg2 = parse_reg_or_err(tokens[2], line);
-    let n = parse_num_or_err(tokens[3], line);
-    (reg1 << 8) | (reg2 << 4) | n
+// Replaces the words of `text` found in `words`. Replacements inside a larger expression are put
+// in parentheses, so `x*2` with `x` = `1+1` becomes `(1+1)*2`.
+fn substitute(text: &str, words: &HashMap<String, String>) -> String {
+    if let Some(replacement) = words.get(text) {
+        return replacement.clone();
+    }
+
+    let mut substituted = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '"' || c == '\'' {
+            rest[1..].find(c).map_or(rest.len(), |end| end + 2)
+        } else if is_word_char(c) {
+            let len = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+            if let Some(replacement) = words.get(&rest[..len]) {
+                substituted.push_str(&format!("({})", replacement));
+                rest = &rest[len..];
+                continue;
+            }
+            len
+        } else {
+            c.len_utf8()
+        };
+        substituted.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    substituted
 }
 
-fn parse_xnn(tokens: Vec<&str>, line: usize) -> u16 {
-    let reg = parse_reg_or_err(tokens[1], line);
-    (reg << 8) | parse_num_or_err(tokens[2], line)
+// Address right after the statement, when it starts at addr
+fn next_addr(
+    s: &Statement,
+    addr: usize,
+    syntax: Syntax,
+    symbols: &Symbols,
+) -> Result<usize, AsmError> {
+    let next = match s.keyword().as_deref() {
+        None => addr,
+        Some(_) if s.is_equ() => addr,
+        Some("mvil") if syntax == Syntax::Native => addr + 4,
+        Some("db") => addr + s.tokens.len() - 1,
+        Some("dw") => addr + (s.tokens.len() - 1) * 2,
+        Some("org") => {
+            let org = s.value(1, symbols, WORD)? as usize;
+            if org < addr {
+                return Err(s.error(
+                    &s.tokens[1],
+                    format!("org {:#x} is before the current address {:#x}", org, addr),
+                ));
+            }
+            org
+        }
+        Some("align") => {
+            let align = s.value(1, symbols, WORD)? as usize;
+            if align == 0 {
+                return Err(s.error(&s.tokens[1], "Alignment must be greater than 0"));
+            }
+            addr.next_multiple_of(align)
+        }
+        Some("incbin") => addr + s.read_incbin()?.len(),
+        Some(_) => addr + 2,
+    };
+    if next > MEMORY_SIZE {
+        return Err(s.error(
+            &s.tokens[0],
+            format!(
+                "`{}` goes past the end of memory at {:#x}",
+                s.tokens[0].text, MEMORY_SIZE
+            ),
+        ));
+    }
+    Ok(next)
 }
 
-// OP vX vY -> 0x_XY_
-fn parse_xy(tokens: Vec<&str>, line: usize) -> u16 {
-    let reg1 = parse_reg_or_err(tokens[1], line);
-    let reg2 = parse_reg_or_err(tokens[2], line);
-    (reg1 << 8) | (reg2 << 4)
+impl Statement {
+    fn error(&self, token: &Token, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.to_string(),
+            line: self.line,
+            column: token.column,
+            len: token.text.chars().count(),
+            message: message.into(),
+            source_line: self.source_line.to_string(),
+        }
+    }
+
+    fn operand(&self, n: usize) -> Result<&Token, AsmError> {
+        self.tokens.get(n).ok_or_else(|| {
+            let last = &self.tokens[self.tokens.len() - 1];
+            let end = Token {
+                text: " ".to_string(),
+                column: last.column + last.text.chars().count(),
+            };
+            self.error(
+                &end,
+                format!("`{}` expects at least {} operands", self.tokens[0].text, n),
+            )
+        })
+    }
+
+    fn reg(&self, n: usize) -> Result<u8, AsmError> {
+        let token = self.operand(n)?;
+        let num = token
+            .text
+            .strip_prefix(['v', 'V'])
+            .ok_or_else(|| self.error(token, "Expected a register"))?;
+        match u8::from_str_radix(num, 16) {
+            Ok(reg) if reg <= 0xF => Ok(reg),
+            _ => Err(self.error(token, "Invalid register, expected v0 to vF")),
+        }
+    }
+
+    // A constant expression
+    fn value(&self, n: usize, symbols: &Symbols, max: u16) -> Result<u16, AsmError> {
+        let token = self.operand(n)?;
+        let value = eval(&token.text, symbols).map_err(|message| self.error(token, message))?;
+        if value < 0 || value > max as i64 {
+            let value = if value < 0 {
+                format!("-{:#x}", value.unsigned_abs())
+            } else {
+                format!("{:#x}", value)
+            };
+            return Err(self.error(
+                token,
+                format!("{} is out of range, expected 0 to {:#x}", value, max),
+            ));
+        }
+        Ok(value as u16)
+    }
+
+    // The first token in lowercase, for mnemonics and directives
+    fn keyword(&self) -> Option<String> {
+        self.tokens.first().map(|t| t.text.to_ascii_lowercase())
+    }
+
+    fn label_only(&self) -> Statement {
+        Statement {
+            tokens: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    // A generated statement, at the same place as this one
+    fn generated(&self, label: Option<String>, tokens: Vec<Token>) -> Statement {
+        let column = self.tokens.first().map_or(1, |t| t.column);
+        Statement {
+            label: label.map(|text| Token { text, column }),
+            exported: false,
+            tokens,
+            ..self.clone()
+        }
+    }
+
+    fn jump(&self, syntax: Syntax, target: String) -> Statement {
+        let column = self.tokens[0].column;
+        let tokens = [syntax.mnemonic("jmp").to_string(), target]
+            .map(|text| Token { text, column })
+            .to_vec();
+        self.generated(None, tokens)
+    }
+
+    // `if vX == nn {` and `while vX != vY {` become a skip over the jump out of the block, taken
+    // when the condition is true
+    fn condition_skip(&self, syntax: Syntax) -> Result<Statement, AsmError> {
+        let tokens = &self.tokens;
+        if tokens.len() != 5 || tokens[4].text != "{" {
+            let message = format!("Expected `{} vX == value {{`", tokens[0].text);
+            return Err(self.error(&tokens[0], message));
+        }
+        let skip = match tokens[2].text.as_str() {
+            "==" => syntax.mnemonic("skeq"),
+            "!=" => syntax.mnemonic("skne"),
+            _ => return Err(self.error(&tokens[2], "Expected `==` or `!=`")),
+        };
+        let skip = Token {
+            text: skip.to_string(),
+            column: tokens[2].column,
+        };
+        Ok(self.generated(None, vec![skip, tokens[1].clone(), tokens[3].clone()]))
+    }
+
+    fn is_equ(&self) -> bool {
+        self.tokens.len() > 1 && self.tokens[1].text.eq_ignore_ascii_case("equ")
+    }
+
+    fn define(&self, symbols: &mut Symbols, name: &Token, value: u16, errors: &mut Vec<AsmError>) {
+        if symbols.insert(name.text.to_string(), value).is_some() {
+            errors.push(self.error(name, format!("`{}` is already defined", name.text)));
+        }
+    }
+
+    // OP vX [vY], vY defaults to v0
+    fn x_or_xy(&self, op: fn(u8, u8) -> Instruction) -> Result<Instruction, AsmError> {
+        let y = if self.tokens.len() > 2 {
+            self.reg(2)?
+        } else {
+            0
+        };
+        Ok(op(self.reg(1)?, y))
+    }
+
+    fn xnn_or_xy(
+        &self,
+        symbols: &Symbols,
+        op_xnn: fn(u8, u8) -> Instruction,
+        op_xy: fn(u8, u8) -> Instruction,
+    ) -> Result<Instruction, AsmError> {
+        let x = self.reg(1)?;
+        if is_register(&self.operand(2)?.text) {
+            Ok(op_xy(x, self.reg(2)?))
+        } else {
+            Ok(op_xnn(x, self.value(2, symbols, BYTE)? as u8))
+        }
+    }
+
+    // A path in quotes, relative to the file the statement is in
+    fn path(&self, n: usize) -> Result<PathBuf, AsmError> {
+        let path = self.operand(n)?.text.trim_matches('"');
+        Ok(match Path::new(&*self.file).parent() {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        })
+    }
+
+    // incbin "file"
+    fn read_incbin(&self) -> Result<Vec<u8>, AsmError> {
+        let path = self.path(1)?;
+        std::fs::read(&path).map_err(|err| {
+            let message = format!("Failed to read {}: {}", path.display(), err);
+            self.error(&self.tokens[1], message)
+        })
+    }
+
+    // include "file"
+    fn include(
+        &self,
+        includes: &mut Vec<PathBuf>,
+        errors: &mut Vec<AsmError>,
+    ) -> Result<Vec<Statement>, AsmError> {
+        let path = self.path(1)?;
+        let (canonical, source) = path
+            .canonicalize()
+            .and_then(|canonical| Ok((canonical, std::fs::read_to_string(&path)?)))
+            .map_err(|err| {
+                let message = format!("Failed to read {}: {}", path.display(), err);
+                self.error(&self.tokens[1], message)
+            })?;
+
+        if includes.contains(&canonical) {
+            let cycle: Vec<_> = includes
+                .iter()
+                .chain([&canonical])
+                .map(|path| path.display().to_string())
+                .collect();
+            let message = format!("Include cycle: {}", cycle.join(" -> "));
+            return Err(self.error(&self.tokens[1], message));
+        }
+
+        includes.push(canonical);
+        let statements = parse_file(&path.to_string_lossy(), &source, includes, errors);
+        includes.pop();
+        Ok(statements)
+    }
 }
 
-fn parse_xnn_or_xy(tokens: Vec<&str>, line: usize, base_xnn: u16, base_xy: u16) -> u16 {
-    let reg = parse_reg_or_err(tokens[1], line);
-    if tokens[2].starts_with("v") {
-        let reg2 = parse_reg_or_err(tokens[2], line);
-        base_xy | (reg << 8) | (reg2 << 4)
+pub(crate) fn parse_u16(token: &str) -> Result<u16, ParseIntError> {
+    if let Some(hex) = token.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(bin) = token.strip_prefix("0b") {
+        u16::from_str_radix(bin, 2)
     } else {
-        base_xnn | (reg << 8) | parse_num_or_err(tokens[2], line)
+        token.parse::<u16>()
     }
 }
 
-fn parse_reg_or_err(token: &str, line: usize) -> u16 {
-    let num = token
-        .strip_prefix("v")
-        .unwrap_or_else(|| panic!("Expected a register at line {}", line));
-    parse_u16(num).unwrap_or_else(|_| panic!("Invalid register at line {}", line))
+// vX, where X is a hex number
+fn is_register(token: &str) -> bool {
+    token
+        .strip_prefix(['v', 'V'])
+        .is_some_and(|num| !num.is_empty() && num.chars().all(|c| c.is_ascii_hexdigit()))
 }
 
-fn parse_num_or_err(token: &str, line: usize) -> u16 {
-    parse_u16(token).unwrap_or_else(|_| panic!("Invalid number at line {}", line))
+// Characters of numbers and symbols
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
 }
 
-fn parse_u16(token: &str) -> Result<u16, ParseIntError> {
-    token
-        .strip_prefix("0x")
-        .map_or_else(|| token.parse::<u16>(), |hex| u16::from_str_radix(hex, 16))
+const OPERATOR_CHARS: [char; 8] = ['+', '-', '*', '/', '&', '|', '<', '>'];
+
+// Binary operators, from the lowest to the highest precedence
+const OPERATORS: [&[&str]; 5] = [&["|"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/"]];
+
+// Evaluates an operand: numbers, 'c' characters and symbols, combined with operators, negation
+// and parentheses
+fn eval(text: &str, symbols: &Symbols) -> Result<i64, String> {
+    let mut parser = ExprParser {
+        rest: text,
+        symbols,
+    };
+    let value = parser.binary(0)?;
+    match parser.peek() {
+        "" => Ok(value),
+        unexpected => Err(format!("Unexpected `{}` in expression", unexpected)),
+    }
+}
+
+struct ExprParser<'a> {
+    rest: &'a str,
+    symbols: &'a Symbols,
+}
+
+impl<'a> ExprParser<'a> {
+    // Next token: an operator, a parenthesis, a character literal or a word
+    fn peek(&self) -> &'a str {
+        let rest = self.rest.trim_start();
+        let len = if rest.starts_with("<<") || rest.starts_with(">>") {
+            2
+        } else if let Some(literal) = rest.strip_prefix('\'') {
+            literal.find('\'').map_or(rest.len(), |end| end + 2)
+        } else {
+            match rest.find(|c| !is_word_char(c)) {
+                Some(0) => rest.chars().next().unwrap().len_utf8(),
+                Some(end) => end,
+                None => rest.len(),
+            }
+        };
+        &rest[..len]
+    }
+
+    fn next(&mut self) -> &'a str {
+        let token = self.peek();
+        self.rest = &self.rest.trim_start()[token.len()..];
+        token
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == OPERATORS.len() {
+            return self.atom();
+        }
+
+        let mut value = self.binary(level + 1)?;
+        while OPERATORS[level].contains(&self.peek()) {
+            let op = self.next();
+            let rhs = self.binary(level + 1)?;
+            value = match op {
+                "|" => Some(value | rhs),
+                "&" => Some(value & rhs),
+                // Bits shifted out of the value are an overflow too
+                "<<" => u32::try_from(rhs)
+                    .ok()
+                    .and_then(|rhs| value.checked_shl(rhs))
+                    .filter(|&shifted| shifted >> rhs == value),
+                ">>" => u32::try_from(rhs)
+                    .ok()
+                    .and_then(|rhs| value.checked_shr(rhs)),
+                "+" => value.checked_add(rhs),
+                "-" => value.checked_sub(rhs),
+                "*" => value.checked_mul(rhs),
+                "/" if rhs == 0 => return Err("Division by zero".to_string()),
+                "/" => value.checked_div(rhs),
+                _ => unreachable!(),
+            }
+            .ok_or_else(|| "Overflow in expression".to_string())?;
+        }
+        Ok(value)
+    }
+
+    fn atom(&mut self) -> Result<i64, String> {
+        let token = self.next();
+        if token == "-" {
+            return self
+                .atom()?
+                .checked_neg()
+                .ok_or_else(|| "Overflow in expression".to_string());
+        }
+        if token == "(" {
+            let value = self.binary(0)?;
+            return match self.next() {
+                ")" => Ok(value),
+                _ => Err("Expected `)`".to_string()),
+            };
+        }
+
+        if let Some(literal) = token.strip_prefix('\'') {
+            let mut chars = literal.strip_suffix('\'').unwrap_or("").chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) if (c as u32) <= 0xFFFF => Ok(c as i64),
+                _ => Err(format!("Invalid character literal `{}`", token)),
+            };
+        }
+
+        match token.chars().next() {
+            None => Err("Expected a value".to_string()),
+            Some(c) if c.is_ascii_digit() => parse_u16(token)
+                .map(i64::from)
+                .map_err(|_| format!("Invalid number `{}`", token)),
+            Some(c) if c.is_alphabetic() || c == '_' || c == '.' => self
+                .symbols
+                .get(token)
+                .map(|&value| value as i64)
+                .ok_or_else(|| format!("Unknown symbol `{}`", token)),
+            Some(_) => Err(format!("Unexpected `{}` in expression", token)),
+        }
+    }
+}
+
+#[test]
+fn test_labels() {
+    let code = assemble(
+        r#"
+            jmp start
+        sprite:
+            db 0xF0 0x90
+        start: mvi sprite
+            mvil sprite
+            jsr start
+        "#,
+    );
+    assert_eq!(
+        code.unwrap(),
+        [0x12, 0x04, 0xF0, 0x90, 0xA2, 0x02, 0xF0, 0x00, 0x02, 0x02, 0x22, 0x04]
+    );
+}
+
+#[test]
+fn test_data_directives() {
+    let path = std::env::temp_dir().join("chip8emu_test_incbin.bin");
+    std::fs::write(&path, [0xAA, 0xBB, 0xCC]).unwrap();
+
+    let code = assemble(&format!(
+        r#"
+            db 0b11110000 0x90
+            dw 0x1234 table
+            align 4
+        table:
+            org 0x20A
+            incbin "{}"
+            end
+            align 4
+        "#,
+        path.display()
+    ));
+    assert_eq!(
+        code.unwrap(),
+        [
+            0xF0, 0x90, 0x12, 0x34, 0x02, 0x08, 0x00, 0x00, 0x00, 0x00, 0xAA, 0xBB, 0xCC, 0x00,
+            0x00, 0x00
+        ]
+    );
+}
+
+#[test]
+fn test_syntax() {
+    let code = assemble(
+        "\tMOV V0, 0x10 // comment\n\
+         Add\tv0,v1 # comment; cls\n\
+         // only a comment\n\
+         sprite v0, v1, 5; mvi 0x300 ; rts",
+    );
+    assert_eq!(
+        code.unwrap(),
+        [0x60, 0x10, 0x80, 0x14, 0xD0, 0x15, 0xA3, 0x00, 0x00, 0xEE]
+    );
+}
+
+#[test]
+fn test_errors() {
+    let errors =
+        assemble("start:; cls; start: cls\njmp nowhere\n  mov v0\nfoo v1; add vG 1").unwrap_err();
+    let messages: Vec<_> = errors
+        .iter()
+        .map(|e| (e.line, e.column, e.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            (1, 14, "`start` is already defined"),
+            (2, 5, "Unknown symbol `nowhere`"),
+            (3, 9, "`mov` expects at least 2 operands"),
+            (4, 1, "Unknown instruction `foo`"),
+            (4, 13, "Invalid register, expected v0 to vF"),
+        ]
+    );
+
+    assert_eq!(
+        errors[1].to_string(),
+        "error: Unknown symbol `nowhere`\n --> <input>:2:5\n  |\n2 | jmp nowhere\n  |     ^^^^^^^"
+    );
+}
+
+#[test]
+fn test_operand_ranges() {
+    let errors = assemble(
+        "add v0 0x1FF; mov v20 1; sprite v0 v1 20; scd 16; jmp 0x1000; mvil 0x1000; db 256",
+    )
+    .unwrap_err();
+    let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        [
+            "0x1ff is out of range, expected 0 to 0xff",
+            "Invalid register, expected v0 to vF",
+            "0x14 is out of range, expected 0 to 0xf",
+            "0x10 is out of range, expected 0 to 0xf",
+            "0x1000 is out of range, expected 0 to 0xfff",
+            "0x100 is out of range, expected 0 to 0xff",
+        ]
+    );
+
+    let errors = assemble("org 0x1000; target: cls; jmp target").unwrap_err();
+    assert_eq!(errors[0].column, 30);
+
+    let errors = assemble("org 0xFFFE; cls; db 1 2").unwrap_err();
+    assert_eq!(
+        errors[0].message,
+        "`db` goes past the end of memory at 0x10000"
+    );
+}
+
+#[test]
+fn test_expressions() {
+    let code = assemble(
+        r#"
+        SPEED equ 4
+        ROW equ SPEED * 2
+            mov v0 SPEED+1
+            mvi sprites + 5*ROW
+            mov v1 (SPEED + 1) << 2 | 1
+            mov v2, 'A'
+            db ' ' 0b101 sprites >> 8, sprites & 0xFF
+            add v3 10 - 2 * 3 - 1
+        sprites:
+        "#,
+    );
+    assert_eq!(
+        code.unwrap(),
+        [0x60, 0x05, 0xA2, 0x36, 0x61, 0x15, 0x62, 0x41, 0x20, 0x05, 0x02, 0x0E, 0x73, 0x03]
+    );
+
+    let errors = assemble("FIRST equ LATER; LATER equ 1; mov v1 (1 + 2; mov v2 1/0").unwrap_err();
+    let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        ["Unknown symbol `LATER`", "Expected `)`", "Division by zero"]
+    );
+}
+
+#[test]
+fn test_negative_values() {
+    // `-1` after a space is an operand of its own, `- 1` continues the expression before it
+    assert_eq!(assemble("db 2 - -1 -(1 - 0x100)").unwrap(), [0x03, 0xFF]);
+
+    let errors = assemble("mov v0 -1").unwrap_err();
+    assert_eq!(
+        errors[0].message,
+        "-0x1 is out of range, expected 0 to 0xff"
+    );
+    assert_eq!(errors[0].column, 8);
+}
+
+#[test]
+fn test_overflow() {
+    let errors =
+        assemble("db 1 << 63; db (0-1) << 63; mov v0 -((0-1) << 63); db 3 << 62").unwrap_err();
+    let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        [
+            "Overflow in expression",
+            "-0x8000000000000000 is out of range, expected 0 to 0xff",
+            "Overflow in expression",
+            "Overflow in expression",
+        ]
+    );
+}
+
+#[test]
+fn test_macros() {
+    let code = assemble(
+        r#"
+        macro draw_at x y
+            mov v0 x
+            mov v1 y
+            sprite v0 v1 5
+        endm
+        macro wait_key key
+        loop:
+            skpr key
+            jmp loop
+        endm
+
+            draw_at 10, 20*2
+            wait_key v3
+            wait_key v4
+            rept 2
+            add v0 1
+            endr
+        "#,
+    );
+    assert_eq!(
+        code.unwrap(),
+        [
+            0x60, 0x0A, 0x61, 0x28, 0xD0, 0x15, 0xE3, 0x9E, 0x12, 0x06, 0xE4, 0x9E, 0x12, 0x0A,
+            0x70, 0x01, 0x70, 0x01
+        ]
+    );
+
+    let errors = assemble(
+        "macro one a; cls; endm\nmacro self; self; endm\none 1 2\nself\nendr\nrept 2; cls",
+    )
+    .unwrap_err();
+    let messages: Vec<_> = errors
+        .iter()
+        .map(|e| (e.line, e.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            (3, "`one` expects 1 arguments, got 2"),
+            (2, "Macros are nested too deeply"),
+            (5, "`endr` without a `rept`"),
+            (6, "`rept` without an `endr`"),
+        ]
+    );
+
+    // Each macro calls the previous one twice, 2^40 calls in total
+    let mut source = "macro m0\nendm\n".to_string();
+    for i in 1..40 {
+        source += &format!("macro m{}\nm{}\nm{}\nendm\n", i, i - 1, i - 1);
+    }
+    for program in [
+        source + "m39",
+        "rept 1000\nrept 1000\nrept 1000\nendr\nendr\nendr".into(),
+    ] {
+        let errors = assemble(&program).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "Macros and blocks expand to too many statements"
+        );
+    }
+}
+
+#[test]
+fn test_include() {
+    let dir = std::env::temp_dir().join("chip8emu_test_include");
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(
+        dir.join("lib/sprites.asm"),
+        "sprite:\n    incbin \"sprite.bin\"\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("lib/sprite.bin"), [0xF0, 0x90]).unwrap();
+    std::fs::write(dir.join("a.asm"), "include \"b.asm\"").unwrap();
+    std::fs::write(dir.join("b.asm"), "cls\ninclude \"a.asm\"").unwrap();
+
+    let main = dir.join("main.asm");
+    let source = "start: mvi sprite; jmp start\ninclude \"lib/sprites.asm\"\n";
+    let assembly = assemble_named(&main.to_string_lossy(), source).unwrap();
+    assert_eq!(assembly.code, [0xA2, 0x04, 0x12, 0x00, 0xF0, 0x90]);
+    assert_eq!(assembly.symbols.to_string(), "0200 start\n0204 sprite\n");
+    assert_eq!(
+        assembly.listing,
+        "0200  a2 04 12 00      start: mvi sprite; jmp start\n\
+         0204                   sprite:\n\
+         0204  f0 90                incbin \"sprite.bin\"\n"
+    );
+
+    let errors = assemble_named(&main.to_string_lossy(), "include \"a.asm\"").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].message.starts_with("Include cycle: "));
+    assert!(errors[0].file.ends_with("b.asm"));
+    assert_eq!(errors[0].line, 2);
+}
+
+#[test]
+fn test_control_flow() {
+    let code = assemble(
+        r#"
+            if v0 == 5 {
+                mov v1 1
+            } else {
+                mov v1 2
+            }
+            loop {
+                add v0 1
+                if v0 != v1 {
+                    break
+                }
+            }
+            while v2 != 0 {
+                call sub
+            }
+        sub:
+            rts
+        "#,
+    );
+    assert_eq!(
+        code.unwrap(),
+        [
+            0x30, 0x05, 0x12, 0x08, 0x61, 0x01, 0x12, 0x0A, 0x61, 0x02, // if/else
+            0x70, 0x01, 0x90, 0x10, 0x12, 0x12, 0x12, 0x14, 0x12, 0x0A, // loop/break
+            0x42, 0x00, 0x12, 0x1C, 0x22, 0x1C, 0x12, 0x14, // while/call
+            0x00, 0xEE,
+        ]
+    );
+
+    // Only the labels written in the source go in the symbol table
+    let source = "macro wait\nagain: skpr v0\njmp again\nendm\nstart: wait\nloop {\nwait\n}\ndone:";
+    let assembly = assemble_named("<input>", source).unwrap();
+    assert_eq!(assembly.symbols.to_string(), "0200 start\n020a done\n");
+
+    let errors = assemble("if v0 = 5 {\nbreak\n}\n}\nloop {").unwrap_err();
+    let messages: Vec<_> = errors
+        .iter()
+        .map(|e| (e.line, e.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            (1, "Expected `==` or `!=`"),
+            (2, "`break` outside of a loop"),
+            (4, "`}` without a block to close"),
+            (5, "`loop` without a closing `}`"),
+        ]
+    );
+}
+
+#[test]
+fn test_cowgod_syntax() {
+    let source = r#"
+        start:
+            CLS
+            LD V0, 0x12
+            LD V1, V0
+            LD I, sprite
+            LD DT, V0
+            LD V2, DT
+            LD V3, K
+            LD F, V3
+            LD B, V3
+            LD [I], V3
+            LD V3, [I]
+            ADD I, V1
+            ADD V1, 2
+            SE V1, V2
+            SNE V1, 3
+            SUBN V1, V2
+            SHR V1
+            RND V4, 0x0F
+            DRW V0, V1, 5
+            SKP V4
+            SKNP V4
+            JP V0, start
+            CALL start
+            SYS 0x123
+            if v0 == 1 {
+                RET
+            }
+            JP start
+        sprite:
+            db 0xFF
+    "#;
+    let code = assemble_with_syntax("<input>", source, Syntax::Cowgod)
+        .unwrap()
+        .code;
+    assert_eq!(
+        code,
+        [
+            0x00, 0xE0, 0x60, 0x12, 0x81, 0x00, 0xA2, 0x38, 0xF0, 0x15, 0xF2, 0x07, 0xF3, 0x0A,
+            0xF3, 0x29, 0xF3, 0x33, 0xF3, 0x55, 0xF3, 0x65, 0xF1, 0x1E, 0x71, 0x02, 0x51, 0x20,
+            0x41, 0x03, 0x81, 0x27, 0x81, 0x06, 0xC4, 0x0F, 0xD0, 0x15, 0xE4, 0x9E, 0xE4, 0xA1,
+            0xB2, 0x00, 0x22, 0x00, 0x01, 0x23, 0x30, 0x01, 0x12, 0x36, 0x00, 0xEE, 0x12, 0x00,
+            0xFF,
+        ]
+    );
+
+    let errors = assemble_with_syntax("<input>", "LD V0\nmov v0 1", Syntax::Cowgod).unwrap_err();
+    let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        ["Invalid operands for `LD`", "Unknown instruction `mov`"]
+    );
+    assert_eq!(assemble("sys 0x2a0").unwrap(), [0x02, 0xA0]);
+}
+
+#[test]
+fn test_hex_registers() {
+    assert_eq!(
+        assemble("mov vA 1; mov vf 2").unwrap(),
+        [0x6A, 0x01, 0x6F, 0x02]
+    );
 }
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use chip8emu::timers::Timers;
+use rodio::{OutputStream, Source};
+
+pub struct Audio {
+    _stream: rodio::OutputStream,
+    sink: rodio::Sink,
+    playing: bool,
+    // Pattern and pitch of the sound currently in the sink
+    current: (Option<[u8; 16]>, u8),
+}
+
+impl Audio {
+    pub fn new() -> Audio {
+        let (stream, stream_handle) = OutputStream::try_default().unwrap();
+        let sink = rodio::Sink::try_new(&stream_handle).unwrap();
+        Audio {
+            _stream: stream,
+            sink,
+            playing: false,
+            current: (None, 0),
+        }
+    }
+
+    pub fn update(&mut self, timers: &Timers) {
+        let sound = (timers.pattern, timers.pitch);
+        if timers.sound > 0 && self.playing && sound != self.current {
+            self.sink.stop();
+            self.playing = false;
+        }
+
+        if timers.sound > 0 && (!self.playing || self.sink.empty()) {
+            let duration = Duration::from_millis(1000 / 60 * timers.sound as u64);
+            match timers.pattern {
+                Some(pattern) => self.sink.append(
+                    PatternSource::new(pattern, timers.playback_rate())
+                        .amplify(0.2)
+                        .take_duration(duration),
+                ),
+                None => self.sink.append(
+                    rodio::source::SineWave::new(440.0)
+                        .amplify(0.2)
+                        .take_duration(duration),
+                ),
+            }
+            self.current = sound;
+            self.playing = true;
+        }
+        if timers.sound == 0 && self.playing {
+            self.playing = false;
+            self.sink.stop();
+        }
+    }
+}
+
+/// Loops over the 128 bits of an XO-CHIP audio pattern, one sample per bit.
+struct PatternSource {
+    pattern: [u8; 16],
+    rate: u32,
+    bit: usize,
+}
+
+impl PatternSource {
+    fn new(pattern: [u8; 16], rate: f32) -> PatternSource {
+        PatternSource {
+            pattern,
+            rate: (rate as u32).max(1),
+            bit: 0,
+        }
+    }
+}
+
+impl Iterator for PatternSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let byte = self.pattern[self.bit / 8];
+        let set = (byte >> (7 - self.bit % 8)) & 1 != 0;
+        self.bit = (self.bit + 1) % 128;
+        Some(if set { 1.0 } else { -1.0 })
+    }
+}
+
+impl Source for PatternSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,389 @@
+use crate::{
+    cpu::{Cpu, CpuError, StepOutcome},
+    fonts,
+    instruction::{self, Instruction},
+    keyboard::Keyboard,
+    memory::{Memory, RomTooLarge, MEMORY_SIZE, PROGRAM_START},
+    quirks::Quirks,
+    screen::Screen,
+    state::{StateError, StateReader, StateWriter},
+    timers::Timers,
+    undo::{UndoLog, UndoRecord},
+};
+
+pub const DEFAULT_CLOCK_SPEED: u64 = 700;
+pub const FRAME_RATE: u64 = 60;
+
+/// A complete CHIP-8 machine, without any terminal or audio attached to it.
+pub struct Chip8 {
+    cpu: Cpu,
+    memory: Memory,
+    screen: Screen,
+    keyboard: Keyboard,
+    timers: Timers,
+    clock_speed: u64,
+}
+
+impl Chip8 {
+    pub fn new(quirks: Quirks) -> Chip8 {
+        let mut memory = Memory::new();
+        memory.load_fonts(fonts::FONT);
+        memory.load_big_fonts(fonts::BIG_FONT);
+
+        Chip8 {
+            cpu: Cpu::new(quirks),
+            memory,
+            screen: Screen::new(),
+            keyboard: Keyboard::new(),
+            timers: Timers::new(),
+            clock_speed: DEFAULT_CLOCK_SPEED,
+        }
+    }
+
+    /// Fails if the ROM doesn't fit in the memory the quirks let the program address.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), RomTooLarge> {
+        let max = self.cpu.quirks().memory_size() - PROGRAM_START;
+        if rom.len() > max {
+            return Err(RomTooLarge { size: rom.len(), max });
+        }
+        self.memory.load_program(rom)
+    }
+
+    pub fn clock_speed(&self) -> u64 {
+        self.clock_speed
+    }
+
+    pub fn set_clock_speed(&mut self, clock_speed: u64) {
+        self.clock_speed = clock_speed;
+    }
+
+    /// Makes the random numbers of Cxnn the same on every run with the same seed.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.cpu.set_seed(seed);
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<StepOutcome, CpuError> {
+        self.timers.update((self.clock_speed / FRAME_RATE).max(1));
+        self.cpu.run(
+            &mut self.memory,
+            &mut self.screen,
+            &mut self.keyboard,
+            &mut self.timers,
+        )
+    }
+
+    /// Executes a single instruction, recording what it changes in `undo`.
+    pub fn step_undoable(&mut self, undo: &mut UndoLog) -> Result<StepOutcome, CpuError> {
+        let cpu = self.cpu.clone();
+        let timers = self.timers.clone();
+        let keyboard = self.keyboard.clone();
+        let (planes, hires) = (self.screen.planes(), self.screen.is_hires());
+        // Most instructions leave the pixels alone, only these need a copy to compare with
+        let pc = self.cpu.pc();
+        let opcode = (pc + 1 < MEMORY_SIZE).then(|| self.memory.get_u16(pc));
+        let screen = match opcode.map(instruction::decode) {
+            Some(Ok(
+                Instruction::Clear
+                | Instruction::ScrollDown(_)
+                | Instruction::ScrollLeft
+                | Instruction::ScrollRight
+                | Instruction::Lores
+                | Instruction::Hires
+                | Instruction::Draw(..),
+            )) => Some(self.screen.clone()),
+            _ => None,
+        };
+
+        self.memory.start_journal();
+        let outcome = self.step();
+        undo.push(UndoRecord {
+            cpu,
+            timers,
+            keyboard,
+            memory: self.memory.take_journal(),
+            pixels: screen.map_or(Vec::new(), |before| self.screen.changes_since(&before)),
+            planes,
+            hires,
+        });
+        outcome
+    }
+
+    /// Puts the machine back as it was before the last instruction in `undo`. Returns false
+    /// when there is nothing to undo.
+    pub fn undo(&mut self, undo: &mut UndoLog) -> bool {
+        let Some(record) = undo.pop() else {
+            return false;
+        };
+        self.cpu = record.cpu;
+        self.timers = record.timers;
+        self.keyboard = record.keyboard;
+        for &(addr, old) in record.memory.iter().rev() {
+            self.memory[addr] = old;
+        }
+        self.screen.undo(&record.pixels, record.planes, record.hires);
+        true
+    }
+
+    /// Executes the instructions that fit in one 60 Hz frame at the current clock speed,
+    /// stopping early if the program halts or waits for the display. The timers tick once.
+    pub fn run_frame(&mut self) -> Result<StepOutcome, CpuError> {
+        let instructions = (self.clock_speed / FRAME_RATE).max(1);
+        for _ in 0..instructions {
+            match self.step()? {
+                StepOutcome::Continue => {}
+                StepOutcome::Halted => return Ok(StepOutcome::Halted),
+                StepOutcome::WaitForVblank => break,
+            }
+        }
+        self.end_frame();
+        Ok(StepOutcome::Continue)
+    }
+
+    /// Ends a frame run one `step` at a time, ticking the timers if the frame was cut short.
+    pub fn end_frame(&mut self) {
+        self.timers.end_frame();
+    }
+
+    /// Snapshot of the whole machine, for `load_state`. The quirks and clock speed aren't part
+    /// of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.cpu.save_state(&mut state);
+        self.memory.save_state(&mut state);
+        self.screen.save_state(&mut state);
+        self.timers.save_state(&mut state);
+        self.keyboard.save_state(&mut state);
+        state.finish()
+    }
+
+    /// Restores a snapshot from `save_state`. The machine is left unchanged if it fails.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let previous = self.save_state();
+        let result = self.read_state(state);
+        if result.is_err() {
+            self.read_state(&previous).expect("a state just saved loads back");
+        }
+        result
+    }
+
+    fn read_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(state)?;
+        self.cpu.load_state(&mut state)?;
+        self.memory.load_state(&mut state)?;
+        self.screen.load_state(&mut state)?;
+        self.timers.load_state(&mut state)?;
+        self.keyboard.load_state(&mut state)?;
+        state.finish()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn set_rpl_flags(&mut self, flags: [u8; 16]) {
+        self.cpu.set_rpl_flags(flags);
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn screen_mut(&mut self) -> &mut Screen {
+        &mut self.screen
+    }
+
+    pub fn keyboard(&self) -> &Keyboard {
+        &self.keyboard
+    }
+
+    pub fn keyboard_mut(&mut self) -> &mut Keyboard {
+        &mut self.keyboard
+    }
+
+    pub fn timers(&self) -> &Timers {
+        &self.timers
+    }
+}
+
+#[test]
+fn test_run_frame() {
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&crate::assembler::assemble(
+        "mov v0 0x2a; font v1; sprite v1 v1 5; end",
+    ).unwrap()).unwrap();
+
+    assert_eq!(chip8.run_frame(), Ok(StepOutcome::Halted));
+    assert_eq!(chip8.cpu().v()[0], 0x2a);
+    assert_eq!(chip8.cpu().pc(), 0x208);
+    assert_eq!(chip8.screen().get(0, 0), 1);
+}
+
+#[test]
+fn test_step_error() {
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&[0x00, 0xEE]).unwrap();
+
+    assert_eq!(chip8.step(), Err(CpuError::StackUnderflow));
+}
+
+#[test]
+fn test_save_state() {
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(
+        &crate::assembler::assemble(
+            "loop: rand v0 0xFF; mvi 0x300; str v0; sprite v0 v0 1; sdelay v0; jmp loop",
+        )
+        .unwrap(),
+    ).unwrap();
+    chip8.keyboard_mut().press(0xA);
+    for _ in 0..12 {
+        chip8.step().unwrap();
+    }
+
+    let state = chip8.save_state();
+    for _ in 0..12 {
+        chip8.step().unwrap();
+    }
+    let after = chip8.save_state();
+
+    // The RNG is restored too, so the same instructions give the same machine
+    chip8.load_state(&state).unwrap();
+    assert_eq!(chip8.save_state(), state);
+    assert!(chip8.keyboard()[0xA]);
+    for _ in 0..12 {
+        chip8.step().unwrap();
+    }
+    assert_eq!(chip8.save_state(), after);
+
+    assert_eq!(chip8.load_state(b"nope"), Err(StateError::NotAState));
+    assert_eq!(
+        chip8.load_state(&state[..state.len() - 1]),
+        Err(StateError::Truncated)
+    );
+    let mut future = state.clone();
+    future[5] = 3;
+    assert_eq!(
+        chip8.load_state(&future),
+        Err(StateError::UnsupportedVersion(3))
+    );
+    assert_eq!(chip8.save_state(), after);
+}
+
+#[test]
+fn test_frame_ticks_timers_once() {
+    // Sets the delay timer to 3 then draws forever, every draw waits for the display
+    let rom = [0x60, 0x03, 0xF0, 0x15, 0xD0, 0x01, 0x12, 0x04];
+    let mut chip8 = Chip8::new(Quirks {
+        display_wait: true,
+        ..Quirks::default()
+    });
+    chip8.load_rom(&rom).unwrap();
+    for delay in [2, 1, 0, 0] {
+        assert_eq!(chip8.run_frame(), Ok(StepOutcome::Continue));
+        assert_eq!(chip8.timers().delay, delay);
+    }
+}
+
+#[test]
+fn test_seed() {
+    // Random numbers in v0 and the delay timer, forever
+    let rom = [0xC0, 0xFF, 0xF0, 0x15, 0x12, 0x00];
+    let run = |seed| {
+        let mut chip8 = Chip8::new(Quirks::default());
+        chip8.load_rom(&rom).unwrap();
+        chip8.set_seed(seed);
+        for _ in 0..1000 {
+            chip8.step().unwrap();
+        }
+        chip8.save_state()
+    };
+    assert_eq!(run(1), run(1));
+    assert_ne!(run(1), run(2));
+
+    // Cxnn masks a random byte with nn
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&[0xC0, 0x0A, 0x12, 0x00]).unwrap();
+    chip8.set_seed(1);
+    let mut seen = [false; 256];
+    for _ in 0..200 {
+        chip8.step().unwrap();
+        seen[chip8.cpu().v()[0] as usize] = true;
+    }
+    let seen: Vec<_> = (0..256).filter(|&v| seen[v]).collect();
+    assert_eq!(seen, [0x00, 0x02, 0x08, 0x0A]);
+}
+
+#[test]
+fn test_timers_follow_instructions() {
+    // Sets the delay timer to 2 then loops
+    let rom = [0x60, 0x02, 0xF0, 0x15, 0x12, 0x04];
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&rom).unwrap();
+    let per_frame = chip8.clock_speed() / FRAME_RATE;
+    chip8.step().unwrap();
+    chip8.step().unwrap();
+    assert_eq!(chip8.timers().delay, 2);
+    for _ in 2..per_frame {
+        chip8.step().unwrap();
+    }
+    assert_eq!(chip8.timers().delay, 1);
+    for _ in 0..per_frame {
+        chip8.step().unwrap();
+    }
+    assert_eq!(chip8.timers().delay, 0);
+}
+
+#[test]
+fn test_undo() {
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(
+        &crate::assembler::assemble(
+            "loop: rand v0 0xFF; mvi 0x300; bcd v0; jsr draw; scr; cls; high; jmp loop
+             draw: sprite v0 v0 3; rts",
+        )
+        .unwrap(),
+    ).unwrap();
+
+    let mut undo = UndoLog::new(100);
+    let mut states = Vec::new();
+    for _ in 0..30 {
+        states.push(chip8.save_state());
+        chip8.step_undoable(&mut undo).unwrap();
+    }
+    while let Some(state) = states.pop() {
+        assert!(chip8.undo(&mut undo));
+        assert_eq!(chip8.save_state(), state);
+    }
+    assert!(!chip8.undo(&mut undo));
+
+    // Ex9E releases the key, undoing it presses the key again
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&[0x60, 0x05, 0xE0, 0x9E, 0x00, 0xE0]).unwrap();
+    chip8.keyboard_mut().press(5);
+    chip8.step_undoable(&mut undo).unwrap();
+    let state = chip8.save_state();
+    chip8.step_undoable(&mut undo).unwrap();
+    assert!(!chip8.keyboard()[5]);
+    assert!(chip8.undo(&mut undo));
+    assert!(chip8.keyboard()[5]);
+    assert_eq!(chip8.save_state(), state);
+}
+
+#[test]
+fn test_rom_too_large() {
+    let mut chip8 = Chip8::new(Quirks::default());
+    assert!(chip8.load_rom(&[0; 0xE00]).is_ok());
+    assert_eq!(chip8.load_rom(&[0; 0xE01]), Err(RomTooLarge { size: 0xE01, max: 0xE00 }));
+
+    let mut chip8 = Chip8::new(Quirks::xochip());
+    assert!(chip8.load_rom(&[0; 0xE01]).is_ok());
+}
@@ -1,147 +1,290 @@
 #[cfg(test)]
 use crate::assembler;
-use rand::Rng;
+use std::fmt;
 
 use crate::{
-    keyboard::Keyboard, memory::{self, Memory}, screen::Screen, timers::Timers
+    instruction::{self, Instruction},
+    keyboard::Keyboard,
+    memory::{self, Memory},
+    quirks::Quirks,
+    screen::Screen,
+    state::{StateError, StateReader, StateWriter},
+    timers::Timers,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Continue,
+    Halted,
+    /// A sprite was drawn with the display wait quirk on, nothing else should run this frame.
+    WaitForVblank,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    UnknownOpcode { pc: usize, opcode: u16 },
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfBounds { addr: usize },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown opcode {:04x} at {:03x}", opcode, pc)
+            }
+            CpuError::StackOverflow => write!(f, "Stack overflow"),
+            CpuError::StackUnderflow => write!(f, "Stack underflow"),
+            CpuError::MemoryOutOfBounds { addr } => {
+                write!(f, "Memory access out of bounds at {:x}", addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+#[derive(Clone)]
 pub struct Cpu {
     v: [u8; 16],
     pc: usize,
     stack_pointer: usize,
     i: u16,
+    rpl: [u8; 16],
+    // xorshift64 state for Cxnn, never 0
+    rng: u64,
 
-    y_shift: bool,
+    quirks: Quirks,
 }
 
 impl Cpu {
-    pub fn new(flags: &[String]) -> Cpu {
+    pub fn new(quirks: Quirks) -> Cpu {
         Cpu {
             v: [0; 16],                // Registers
             pc: memory::PROGRAM_START, // Program counter
             stack_pointer: 0,          // Stack pointer
             i: 0,                      // Index register
-            y_shift: flags.iter().any(|s| s == "--yshift"),
+            rpl: [0; 16],              // SUPER-CHIP RPL user flags
+            rng: rand::random::<u64>() | 1, // Random seed, see set_seed
+            quirks,
         }
     }
 
-    fn read_opcode(&self, memory: &Memory) -> u16 {
-        let p = self.pc;
-        let most_significant = memory[p] as u16;
-        let least_significant = memory[p + 1] as u16;
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
 
-        most_significant << 8 | least_significant
+    pub fn v(&self) -> &[u8; 16] {
+        &self.v
     }
 
-    #[rustfmt::skip]
-    pub fn run(&mut self, memory: &mut Memory, screen: &mut Screen, keyboard: &mut Keyboard, timers: &mut Timers) -> bool {
-        let opcode = self.read_opcode(memory);
-        self.pc += 2;
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
 
-        let c = ((opcode & 0xF000) >> 12) as u8;
-        let x = ((opcode & 0x0F00) >> 8) as u8;
-        let y = ((opcode & 0x00F0) >> 4) as u8;
-        let d = (opcode & 0x000F) as u8;
+    pub fn stack_pointer(&self) -> usize {
+        self.stack_pointer
+    }
 
-        let n = (opcode & 0x000F) as u8;
-        let nn = (opcode & 0x00FF) as u8;
-        let nnn = opcode & 0x0FFF;
+    pub fn i(&self) -> u16 {
+        self.i
+    }
 
-        let x_val = self.v[x as usize];
-        let y_val = self.v[y as usize];
+    pub fn rpl_flags(&self) -> &[u8; 16] {
+        &self.rpl
+    }
+
+    pub fn set_rpl_flags(&mut self, flags: [u8; 16]) {
+        self.rpl = flags;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        // splitmix64, so close seeds give unrelated sequences
+        let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        self.rng = (z ^ (z >> 31)).max(1);
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.v);
+        state.u32(self.pc as u32);
+        state.u8(self.stack_pointer as u8);
+        state.u16(self.i);
+        state.bytes(&self.rpl);
+        state.u64(self.rng);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.v = state.array()?;
+        self.pc = state.u32()? as usize;
+        self.stack_pointer = state.u8()? as usize;
+        self.i = state.u16()?;
+        self.rpl = state.array()?;
+        self.rng = state.u64()?;
+        if self.stack_pointer > memory::STACK_SIZE || self.rng == 0 {
+            return Err(StateError::Invalid);
+        }
+        Ok(())
+    }
 
-        match (c, x, y, d) {
-            (  0,   0,   0,   0) => { return false;}
-            (  0,   0, 0xE,   0) => self.clear_screen(screen),
-            (  0,   0, 0xE, 0xE) => self.ret(memory),
-            (0x1,   _,   _,   _) => self.jump(nnn),
-            (0x2,   _,   _,   _) => self.call(nnn, memory),
-            (0x3,   _,   _,   _) => self.skip_if_eq(x_val, nn),
-            (0x4,   _,   _,   _) => self.skip_if_neq(x_val, nn),
-            (0x5,   _,   _,   0) => self.skip_if_eq(x_val, y_val),
-            (0x6,   _,   _,   _) => self.v[x as usize] = nn, // vX := nn
-            (0x7,   _,   _,   _) => { 
-                self.v[x as usize] = self.v[x as usize].wrapping_add(nn) 
-            }, // add vX nn
-            (0x8,   _,   _, 0x0) => self.v[x as usize] = self.v[y as usize],
-            (0x8,   _,   _, 0x1) => self.v[x as usize] = x_val | y_val, // or
-            (0x8,   _,   _, 0x2) => self.v[x as usize] = x_val & y_val, // and
-            (0x8,   _,   _, 0x3) => self.v[x as usize] = x_val ^ y_val, // xor
-            (0x8,   _,   _, 0x4) => self.add_xy(x, y),
-            (0x8,   _,   _, 0x5) => self.sub_xy(x, y),
-            (0x8,   _,   _, 0x6) => self.shift_right(x, y),
-            (0x8,   _,   _, 0x7) => self.rsb_xy(x, y),
-            (0x8,   _,   _, 0xE) => self.shift_left(x, y),
-            (0x9,   _,   _,   _) => self.skip_if_neq(x_val, y_val),
-            (0xA,   _,   _,   _) => self.i = nnn, // i := nnn
-            (0xB,   _,   _,   _) => self.pc = nnn as usize + self.v[0] as usize,
-            (0xC,   _,   _,   _) => self.rand(x, nn),
-            (0xD,   _,   _,   _) => self.draw_xyn(memory, screen, x, y, n),
-            (0xE,   _, 0x9, 0xE) => self.skip_if_key(keyboard, x_val, true),
-            (0xE,   _, 0xA, 0x1) => self.skip_if_key(keyboard, x_val, false),
-            (0xF,   _, 0x1, 0xE) => self.i += x_val as u16,
-            (0xF,   _, 0x0, 0xA) => self.wait_for(keyboard[x_val as usize]),
-            (0xF,   _, 0x2, 0x9) => self.set_i_to_font_addr(x_val),
-            (0xF,   _, 0x3, 0x3) => self.bcd_x_to_i(memory, x),
-            (0xF,   _, 0x5, 0x5) => self.store_reg_at_i(memory, x),
-            (0xF,   _, 0x6, 0x5) => self.load_reg_at_i(memory, x),
-            (0xF,   _, 0x0, 0x7) => self.v[x as usize] = timers.delay,
-            (0xF,   _, 0x1, 0x5) => timers.delay = x_val,
-            (0xF,   _, 0x1, 0x8) => timers.sound = x_val,
-            _ => panic!("Unknown opcode: {:x}", opcode),
+    fn read_opcode(&self, memory: &Memory) -> Result<u16, CpuError> {
+        let p = self.check_addr(self.pc)?;
+        let most_significant = memory[p] as u16;
+        let least_significant = memory[self.check_addr(p + 1)?] as u16;
+
+        Ok(most_significant << 8 | least_significant)
+    }
+
+    pub fn run(
+        &mut self,
+        memory: &mut Memory,
+        screen: &mut Screen,
+        keyboard: &mut Keyboard,
+        timers: &mut Timers,
+    ) -> Result<StepOutcome, CpuError> {
+        let opcode = self.read_opcode(memory)?;
+        let instruction = instruction::decode(opcode)
+            .map_err(|_| CpuError::UnknownOpcode { pc: self.pc, opcode })?;
+        self.pc += 2;
+        self.execute(instruction, memory, screen, keyboard, timers)
+    }
+
+    #[rustfmt::skip]
+    fn execute(&mut self, instruction: Instruction, memory: &mut Memory, screen: &mut Screen, keyboard: &mut Keyboard, timers: &mut Timers) -> Result<StepOutcome, CpuError> {
+        use Instruction::*;
+
+        match instruction {
+            End => { return Ok(StepOutcome::Halted); }
+            Clear => self.clear_screen(screen),
+            Return => self.ret(memory)?,
+            ScrollDown(n) => screen.scroll_down(n as usize),
+            ScrollRight => screen.scroll_right(4),
+            ScrollLeft => screen.scroll_left(4),
+            Exit => { return Ok(StepOutcome::Halted); }
+            Lores => screen.set_hires(false),
+            Hires => screen.set_hires(true),
+            Jump(nnn) => self.jump(nnn),
+            Call(nnn) => self.call(nnn, memory)?,
+            SkipEqImm(x, nn) => self.skip_if_eq(memory, self.v[x as usize], nn),
+            SkipNeqImm(x, nn) => self.skip_if_neq(memory, self.v[x as usize], nn),
+            SkipEqReg(x, y) => self.skip_if_eq(memory, self.v[x as usize], self.v[y as usize]),
+            StoreRange(x, y) => self.store_reg_range_at_i(memory, x, y)?,
+            LoadRange(x, y) => self.load_reg_range_at_i(memory, x, y)?,
+            SetImm(x, nn) => self.v[x as usize] = nn,
+            AddImm(x, nn) => self.v[x as usize] = self.v[x as usize].wrapping_add(nn),
+            Move(x, y) => self.v[x as usize] = self.v[y as usize],
+            Or(x, y) => self.logic_xy(x, self.v[x as usize] | self.v[y as usize]),
+            And(x, y) => self.logic_xy(x, self.v[x as usize] & self.v[y as usize]),
+            Xor(x, y) => self.logic_xy(x, self.v[x as usize] ^ self.v[y as usize]),
+            Add(x, y) => self.add_xy(x, y),
+            Sub(x, y) => self.sub_xy(x, y),
+            ShiftRight(x, y) => self.shift_right(x, y),
+            SubReverse(x, y) => self.rsb_xy(x, y),
+            ShiftLeft(x, y) => self.shift_left(x, y),
+            SkipNeqReg(x, y) => self.skip_if_neq(memory, self.v[x as usize], self.v[y as usize]),
+            SetI(nnn) => self.i = nnn,
+            JumpOffset(nnn) => self.jump_offset((nnn >> 8) as u8, nnn),
+            Rand(x, nn) => self.rand(x, nn),
+            Draw(x, y, n) => {
+                self.draw_xyn(memory, screen, x, y, n)?;
+                if self.quirks.display_wait {
+                    return Ok(StepOutcome::WaitForVblank);
+                }
+            }
+            SkipKey(x) => self.skip_if_key(memory, keyboard, self.v[x as usize], true),
+            SkipNotKey(x) => self.skip_if_key(memory, keyboard, self.v[x as usize], false),
+            SetILong => self.load_long_i(memory)?,
+            Plane(n) => screen.select_planes(n),
+            Audio => self.load_audio_pattern(memory, timers)?,
+            Pitch(x) => timers.pitch = self.v[x as usize],
+            AddI(x) => self.add_i(self.v[x as usize]),
+            WaitKey(x) => self.wait_for(keyboard[(self.v[x as usize] & 0xF) as usize]),
+            Font(x) => self.set_i_to_font_addr(self.v[x as usize]),
+            BigFont(x) => self.set_i_to_big_font_addr(self.v[x as usize]),
+            Bcd(x) => self.bcd_x_to_i(memory, x)?,
+            Store(x) => self.store_reg_at_i(memory, x)?,
+            Load(x) => self.load_reg_at_i(memory, x)?,
+            GetDelay(x) => self.v[x as usize] = timers.delay,
+            SetDelay(x) => timers.delay = self.v[x as usize],
+            SetSound(x) => timers.sound = self.v[x as usize],
+            StoreFlags(x) => self.rpl[..=x as usize].copy_from_slice(&self.v[..=x as usize]),
+            LoadFlags(x) => self.v[..=x as usize].copy_from_slice(&self.rpl[..=x as usize]),
         };
-        true
+        Ok(StepOutcome::Continue)
     }
 
     fn clear_screen(&mut self, screen: &mut Screen) {
         screen.clear();
     }
 
-    fn ret(&mut self, memory: &mut Memory) {
+    fn ret(&mut self, memory: &mut Memory) -> Result<(), CpuError> {
         if self.stack_pointer == 0 {
-            panic!("Stack underflow");
+            return Err(CpuError::StackUnderflow);
         }
 
         self.stack_pointer -= 1;
         self.pc = memory.get_stack_addr(self.stack_pointer) as usize;
+        Ok(())
     }
 
     fn jump(&mut self, addr: u16) {
         self.pc = addr as usize;
     }
 
-    fn call(&mut self, addr: u16, memory: &mut Memory) {
-        if self.stack_pointer > memory::STACK_SIZE {
-            panic!("Stack overflow");
+    fn jump_offset(&mut self, x: u8, nnn: u16) {
+        let offset = if self.quirks.jump_uses_vx {
+            self.v[x as usize]
+        } else {
+            self.v[0]
+        };
+        self.pc = nnn as usize + offset as usize;
+    }
+
+    fn call(&mut self, addr: u16, memory: &mut Memory) -> Result<(), CpuError> {
+        if self.stack_pointer >= memory::STACK_SIZE {
+            return Err(CpuError::StackOverflow);
         }
 
         memory.set_stack_addr(self.stack_pointer, self.pc as u16);
         self.stack_pointer += 1;
         self.pc = addr as usize;
+        Ok(())
     }
 
-    fn skip_if_eq<T>(&mut self, x: T, nn: T) 
+    fn skip_if_eq<T>(&mut self, memory: &Memory, x: T, nn: T) 
     where 
         T: PartialEq
     {
         if x == nn {
-            self.pc += 2;
+            self.skip(memory);
         }
     }
 
-    fn skip_if_neq<T>(&mut self, x: T, nn: T) 
+    fn skip_if_neq<T>(&mut self, memory: &Memory, x: T, nn: T) 
     where 
         T: PartialEq
     {
         if x != nn {
+            self.skip(memory);
+        }
+    }
+
+    // F000 nnnn is the only 4 byte instruction, and gets skipped as a whole
+    fn skip(&mut self, memory: &Memory) {
+        if self.pc + 1 < memory::MEMORY_SIZE && memory.get_u16(self.pc) == 0xF000 {
+            self.pc += 4;
+        } else {
             self.pc += 2;
         }
     }
 
-    fn skip_if_key(&mut self, keyboard: &mut Keyboard, key: u8, val: bool) {
-        self.skip_if_eq(keyboard[key as usize], val);
-        keyboard[key as usize] = false;
+    // Only the low nibble of the key counts, like on the VIP
+    fn skip_if_key(&mut self, memory: &Memory, keyboard: &mut Keyboard, key: u8, val: bool) {
+        let key = (key & 0xF) as usize;
+        self.skip_if_eq(memory, keyboard[key], val);
+        keyboard[key] = false;
     }
 
     fn wait_for(&mut self, val: bool) {
@@ -150,6 +293,13 @@ impl Cpu {
         }
     }
 
+    fn logic_xy(&mut self, x: u8, result: u8) {
+        self.v[x as usize] = result;
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+    }
+
     fn add_xy(&mut self, x: u8, y: u8) {
         let x_val = self.v[x as usize];
         let y_val = self.v[y as usize];
@@ -179,7 +329,7 @@ impl Cpu {
     }
 
     fn shift_left(&mut self, x: u8, y: u8) {
-        let val = if self.y_shift {
+        let val = if self.quirks.shift_uses_vy {
             self.v[y as usize]
         } else {
             self.v[x as usize]
@@ -190,7 +340,7 @@ impl Cpu {
     }
 
     fn shift_right(&mut self, x: u8, y: u8) {
-        let val = if self.y_shift {
+        let val = if self.quirks.shift_uses_vy {
             self.v[y as usize]
         } else {
             self.v[x as usize]
@@ -200,53 +350,143 @@ impl Cpu {
         self.v[0xF] = val & 1;
     }
 
-    fn bcd_x_to_i(&self, memory: &mut Memory, x: u8) {
+    fn bcd_x_to_i(&self, memory: &mut Memory, x: u8) -> Result<(), CpuError> {
         let x_val = self.v[x as usize];
+        self.check_addr(self.i as usize + 2)?;
 
-        memory[self.i as usize] = (x_val / 100) % 10;
-        memory[self.i as usize + 1] = (x_val / 10) % 10;
-        memory[self.i as usize + 2] = x_val % 10;
+        memory.write(self.i as usize, (x_val / 100) % 10);
+        memory.write(self.i as usize + 1, (x_val / 10) % 10);
+        memory.write(self.i as usize + 2, x_val % 10);
+        Ok(())
     }
 
-    fn draw_xyn(&mut self, memory: &Memory, screen: &mut Screen, x: u8, y: u8, n: u8) {
+    fn draw_xyn(
+        &mut self,
+        memory: &mut Memory,
+        screen: &mut Screen,
+        x: u8,
+        y: u8,
+        n: u8,
+    ) -> Result<(), CpuError> {
         self.v[0xF] = 0;
-        let x_val = self.v[x as usize] as usize % 64;
-        let y_val = self.v[y as usize] as usize % 32;
-
-        for row in 0..n as usize {
-            let sprite = memory[row + self.i as usize];
+        let width = screen.width();
+        let height = screen.height();
+        let x_val = self.v[x as usize] as usize % width;
+        let y_val = self.v[y as usize] as usize % height;
+
+        // Dxy0 draws a 16x16 sprite, two bytes per row
+        let (sprite_width, rows) = if n == 0 { (16, 16) } else { (8, n as usize) };
+        let bytes_per_row = sprite_width / 8;
+
+        // With both XO-CHIP planes selected, the sprite for the second plane follows the first
+        let mut addr = self.i as usize;
+        for plane in [1, 2] {
+            if screen.planes() & plane == 0 {
+                continue;
+            }
 
-            for col in 0..8 {
-                let screen_x = x_val + col;
-                let screen_y = y_val + row;
+            for row in 0..rows {
+                let mut sprite = 0u16;
+                for byte in 0..bytes_per_row {
+                    let row_addr = addr + row * bytes_per_row + byte;
+                    sprite = sprite << 8 | memory.read(self.check_addr(row_addr)?) as u16;
+                }
 
-                if screen_x >= 64 {
-                    break;
+                let mut screen_y = y_val + row;
+                if self.quirks.sprite_wrap {
+                    screen_y %= height;
                 }
-                if screen_y >= 32 {
-                    return;
+                if screen_y >= height {
+                    break;
                 }
 
-                let bit = ((sprite >> (7 - col)) & 1) != 0;
-                let screen_state = screen[screen_x][screen_y];
-
-                if bit && screen_state {
-                    self.v[0xF] = 1;
+                for col in 0..sprite_width {
+                    let mut screen_x = x_val + col;
+                    if self.quirks.sprite_wrap {
+                        screen_x %= width;
+                    }
+                    if screen_x >= width {
+                        break;
+                    }
+
+                    let bit = ((sprite >> (sprite_width - 1 - col)) & 1) != 0;
+                    if !bit {
+                        continue;
+                    }
+                    if screen[screen_x][screen_y] & plane != 0 {
+                        self.v[0xF] = 1;
+                    }
+                    screen[screen_x][screen_y] ^= plane;
                 }
-                screen[screen_x][screen_y] = bit ^ screen_state;
             }
+            addr += rows * bytes_per_row;
         }
+        Ok(())
     }
 
-    fn store_reg_at_i(&self, memory: &mut Memory, x: u8) {
+    fn store_reg_at_i(&mut self, memory: &mut Memory, x: u8) -> Result<(), CpuError> {
+        self.check_addr(self.i as usize + x as usize)?;
         for i in 0..=x as usize {
-            memory[self.i as usize + i] = self.v[i];
+            memory.write(self.i as usize + i, self.v[i]);
         }
+        if self.quirks.memory_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+        Ok(())
     }
 
-    fn load_reg_at_i(&mut self, memory: &Memory, x: u8) {
+    fn load_reg_at_i(&mut self, memory: &mut Memory, x: u8) -> Result<(), CpuError> {
+        self.check_addr(self.i as usize + x as usize)?;
         for i in 0..=x as usize {
-            self.v[i] = memory[self.i as usize + i];
+            self.v[i] = memory.read(self.i as usize + i);
+        }
+        if self.quirks.memory_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+        Ok(())
+    }
+
+    fn store_reg_range_at_i(&self, memory: &mut Memory, x: u8, y: u8) -> Result<(), CpuError> {
+        let count = x.abs_diff(y) as usize;
+        self.check_addr(self.i as usize + count)?;
+        for offset in 0..=count {
+            let reg = if x <= y { x as usize + offset } else { x as usize - offset };
+            memory.write(self.i as usize + offset, self.v[reg]);
+        }
+        Ok(())
+    }
+
+    fn load_reg_range_at_i(&mut self, memory: &mut Memory, x: u8, y: u8) -> Result<(), CpuError> {
+        let count = x.abs_diff(y) as usize;
+        self.check_addr(self.i as usize + count)?;
+        for offset in 0..=count {
+            let reg = if x <= y { x as usize + offset } else { x as usize - offset };
+            self.v[reg] = memory.read(self.i as usize + offset);
+        }
+        Ok(())
+    }
+
+    fn load_long_i(&mut self, memory: &Memory) -> Result<(), CpuError> {
+        self.check_addr(self.pc + 1)?;
+        self.i = memory.get_u16(self.pc);
+        self.pc += 2;
+        Ok(())
+    }
+
+    fn load_audio_pattern(&self, memory: &mut Memory, timers: &mut Timers) -> Result<(), CpuError> {
+        let mut pattern = [0; 16];
+        self.check_addr(self.i as usize + pattern.len() - 1)?;
+        for (offset, byte) in pattern.iter_mut().enumerate() {
+            *byte = memory.read(self.i as usize + offset);
+        }
+        timers.pattern = Some(pattern);
+        Ok(())
+    }
+
+    fn add_i(&mut self, x_val: u8) {
+        self.i = self.i.wrapping_add(x_val as u16);
+        if self.quirks.i_overflow_vf {
+            self.v[0xF] = (self.i > 0xFFF) as u8;
         }
     }
 
@@ -254,14 +494,32 @@ impl Cpu {
         self.i = (memory::FONT_START as u16) + (x as u16) * 5;
     }
 
+    fn set_i_to_big_font_addr(&mut self, x: u8) {
+        self.i = (memory::BIG_FONT_START as u16) + (x as u16) * 10;
+    }
+
     fn rand(&mut self, x: u8, nn: u8) {
-        self.v[x as usize] = rand::thread_rng().gen_range(0..=nn);
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.v[x as usize] = self.rng as u8 & nn;
+    }
+
+    fn check_addr(&self, addr: usize) -> Result<usize, CpuError> {
+        if addr < self.quirks.memory_size() {
+            Ok(addr)
+        } else {
+            Err(CpuError::MemoryOutOfBounds { addr })
+        }
     }
 }
 
 #[cfg(test)]
 macro_rules! cpu_test {
-    ($asm:literal [ $($reg_in:expr),+  $(,)?] => [ $($reg_out:expr),+  $(,)?]) =>
+    ($asm:literal [ $($reg_in:expr),+  $(,)?] => [ $($reg_out:expr),+  $(,)?]) => {
+        cpu_test!(Quirks::default(), $asm [$($reg_in),+] => [$($reg_out),+])
+    };
+    ($quirks:expr, $asm:literal [ $($reg_in:expr),+  $(,)?] => [ $($reg_out:expr),+  $(,)?]) =>
 
     {
     #[allow(unused_assignments)]
@@ -269,11 +527,11 @@ macro_rules! cpu_test {
         let mut cpu = Cpu {
             v: [0; 16],
             pc: memory::PROGRAM_START,
-            ..Cpu::new(&[])
+            ..Cpu::new($quirks)
         };
 
         let mut memory = Memory::new();
-        let mut screen = Screen::new(false);
+        let mut screen = Screen::new();
         let mut keyboard = Keyboard::new();
         let mut timers = Timers::new();
 
@@ -283,11 +541,13 @@ macro_rules! cpu_test {
         p += 1;
         )+
 
-        let code = assembler::assemble($asm);
-        memory.load_program(&code);
+        let code = assembler::assemble($asm).unwrap();
+        memory.load_fonts(crate::fonts::FONT);
+        memory.load_big_fonts(crate::fonts::BIG_FONT);
+        memory.load_program(&code).unwrap();
 
         let mut count = 0;
-        while cpu.run(&mut memory, &mut screen, &mut keyboard, &mut timers) {
+        while cpu.run(&mut memory, &mut screen, &mut keyboard, &mut timers).unwrap() != StepOutcome::Halted {
             count += 1;
             if count > 10000 {
                 panic!("Looped for too long for a test (10000 iterations)");
@@ -309,6 +569,34 @@ macro_rules! cpu_test {
     }};
 }
 
+#[cfg(test)]
+macro_rules! cpu_err_test {
+    ($asm:literal => $err:expr) => {{
+        let mut cpu = Cpu::new(Quirks::default());
+        let mut memory = Memory::new();
+        let mut screen = Screen::new();
+        let mut keyboard = Keyboard::new();
+        let mut timers = Timers::new();
+
+        memory.load_program(&assembler::assemble($asm).unwrap()).unwrap();
+
+        let mut count = 0;
+        let err = loop {
+            match cpu.run(&mut memory, &mut screen, &mut keyboard, &mut timers) {
+                Ok(StepOutcome::Continue | StepOutcome::WaitForVblank) => {}
+                Ok(StepOutcome::Halted) => panic!("Program halted without an error"),
+                Err(err) => break err,
+            }
+            count += 1;
+            if count > 10000 {
+                panic!("Looped for too long for a test (10000 iterations)");
+            }
+        };
+
+        assert_eq!(err, $err);
+    }};
+}
+
 #[test]
 fn test_call_and_ret() {
     cpu_test!(r#"
@@ -448,3 +736,178 @@ fn test_bce() {
         [123] => [1, 2, 3]
     )
 }
+
+#[test]
+fn test_errors() {
+    cpu_err_test!("rts" => CpuError::StackUnderflow);
+    cpu_err_test!("jsr 0x200" => CpuError::StackOverflow);
+    cpu_err_test!("mvi 0xFFF; bcd v0" => CpuError::MemoryOutOfBounds { addr: 0x1001 });
+    cpu_err_test!("mvi 0xFFE; str v3" => CpuError::MemoryOutOfBounds { addr: 0x1001 });
+    cpu_err_test!("jmp 0xFFF" => CpuError::MemoryOutOfBounds { addr: 0x1000 });
+    cpu_err_test!("mvil 0x1000; ldr v0" => CpuError::MemoryOutOfBounds { addr: 0x1000 });
+    cpu_err_test!(
+        "mov v0 0xFF; mvi 0x206; str v0; end"
+        => CpuError::UnknownOpcode { pc: 0x206, opcode: 0xFF00 }
+    );
+}
+
+#[test]
+fn test_keys() {
+    let mut cpu = Cpu::new(Quirks::default());
+    let mut memory = Memory::new();
+    let mut screen = Screen::new();
+    let mut keyboard = Keyboard::new();
+    let mut timers = Timers::new();
+
+    // 0x25 is key 5
+    let program = assembler::assemble("mov v0 0x25; skpr v0; end; key v0; add v1 1; end").unwrap();
+    memory.load_program(&program).unwrap();
+    keyboard.press(5);
+    assert_eq!(cpu.run(&mut memory, &mut screen, &mut keyboard, &mut timers), Ok(StepOutcome::Continue));
+    assert_eq!(cpu.run(&mut memory, &mut screen, &mut keyboard, &mut timers), Ok(StepOutcome::Continue));
+    assert_eq!(cpu.pc, 0x206);
+    assert!(!keyboard[5]);
+
+    keyboard.press(5);
+    while cpu.run(&mut memory, &mut screen, &mut keyboard, &mut timers).unwrap() != StepOutcome::Halted {}
+    assert_eq!(cpu.v[1], 1);
+}
+
+#[test]
+fn test_quirks() {
+    let vip = Quirks::cosmac_vip();
+    let schip = Quirks::superchip();
+
+    cpu_test!(vip, "shr v0 v1" [0x00, 0b011] => [0b01, 0b011]);
+    cpu_test!(schip, "shr v0 v1" [0x00, 0b011] => [0x00, 0b011]);
+
+    cpu_test!(vip, "mov vF 1; or v0 v1" [0b001, 0b010] => [
+        0b011, 0b010, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00
+    ]);
+    cpu_test!(schip, "mov vF 1; or v0 v1" [0b001, 0b010] => [
+        0b011, 0b010, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x01
+    ]);
+
+    cpu_test!(vip, "mvi 0x250; str v1; ldr v1" [1, 2] => [0, 0]);
+    cpu_test!(schip, "mvi 0x250; str v1; ldr v1" [1, 2] => [1, 2]);
+
+    cpu_test!(vip, "mov v2 2; jmi 0x204; add v0 1; end" [0] => [1]);
+    cpu_test!(schip, "mov v2 2; jmi 0x204; add v0 1; end" [0] => [0]);
+
+    // The top of a 0 at x 62 covers x 0 and 1 when it wraps, so drawing it at x 0 collides
+    cpu_test!(Quirks::chip48(), "mov v0 62; font v2; sprite v0 v2 1; sprite v1 v2 1" [0, 0, 0] => [
+        62, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x01
+    ]);
+    cpu_test!(schip, "mov v0 62; font v2; sprite v0 v2 1; sprite v1 v2 1" [0, 0, 0] => [
+        62, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00
+    ]);
+
+    let overflow = Quirks {
+        i_overflow_vf: true,
+        ..Quirks::default()
+    };
+    cpu_test!(overflow, "mvi 0xFFF; mov v0 1; adi v0" [0] => [
+        0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x01
+    ]);
+    cpu_test!(schip, "mvi 0xFFF; mov v0 1; adi v0" [0] => [
+        0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00
+    ]);
+
+    for (quirks, outcome) in [(vip, StepOutcome::WaitForVblank), (schip, StepOutcome::Continue)] {
+        let mut cpu = Cpu::new(quirks);
+        let mut memory = Memory::new();
+        memory.load_program(&assembler::assemble("sprite v0 v0 1").unwrap()).unwrap();
+        let result = cpu.run(&mut memory, &mut Screen::new(), &mut Keyboard::new(), &mut Timers::new());
+        assert_eq!(result, Ok(outcome));
+    }
+}
+
+#[test]
+fn test_superchip() {
+    cpu_test!("mov v0 1; sflags v1; mov v0 0; mov v1 0; gflags v1" [0, 2] => [1, 2]);
+    cpu_test!("mov v0 7; xfont v0; ldr v0" [0] => [0xFF]);
+    cpu_test!("exit; add v0 1" [0] => [0]);
+}
+
+#[test]
+fn test_superchip_screen() {
+    let mut cpu = Cpu::new(Quirks::default());
+    let mut memory = Memory::new();
+    let mut screen = Screen::new();
+    let mut keyboard = Keyboard::new();
+    let mut timers = Timers::new();
+    memory.load_big_fonts(crate::fonts::BIG_FONT);
+
+    memory.load_program(&assembler::assemble(
+        "high; mov v0 120; mov v1 60; mvi 0xA0; xsprite v0 v1; scd 2; scl; end",
+    ).unwrap()).unwrap();
+    while cpu.run(&mut memory, &mut screen, &mut keyboard, &mut timers).unwrap() != StepOutcome::Halted {}
+
+    assert!(screen.is_hires());
+    assert_eq!((screen.width(), screen.height()), (128, 64));
+    // Top left corner of the 16x16 sprite, after scrolling down 2 and left 4
+    assert_eq!(screen[116][62], 1);
+    assert_eq!(screen[115][62], 0);
+    assert_eq!(screen[116][61], 0);
+}
+
+#[test]
+fn test_xochip() {
+    let quirks = Quirks { memory_64k: true, ..Quirks::default() };
+    cpu_test!(quirks, "mvil 0x1234; strr v1 v0; ldr v1" [0x12, 0x34] => [0x34, 0x12]);
+    cpu_test!("mvi 0x300; strr v0 v1; mov v0 0; mov v1 0; ldrr v0 v1" [5, 6] => [5, 6]);
+    cpu_test!("skeq v0 0; mvil 0x300; add v1 1" [0, 0] => [0, 1]);
+    cpu_test!(quirks, "mvil 0xFFF0; str v0; mvi 0; mvil 0xFFF0; ldr v1" [7, 8] => [7, 0]);
+}
+
+#[test]
+fn test_i_wraps() {
+    // I wraps around to the font at 0x050, whose first byte is 0xF0
+    let quirks = Quirks { memory_64k: true, memory_increments_i: true, ..Quirks::default() };
+    cpu_test!(quirks, "mvil 0xFFF0; adi v0; ldr v0" [0x60] => [0xF0]);
+    cpu_test!(quirks, "mvil 0xFFFE; str v1; adi v2; ldr v0" [1, 2, 0x50] => [0xF0, 2, 0x50]);
+    cpu_test!(quirks, "mvil 0xFFFE; ldr v1; adi v2; ldr v0" [1, 2, 0x50] => [0xF0, 0, 0x50]);
+}
+
+#[test]
+fn test_xochip_planes() {
+    let mut cpu = Cpu::new(Quirks::xochip());
+    let mut memory = Memory::new();
+    let mut screen = Screen::new();
+    let mut keyboard = Keyboard::new();
+    let mut timers = Timers::new();
+
+    // 0x80 on the first plane, 0xC0 on the second
+    memory.load_program(&assembler::assemble(
+        "mov v0 0x80; mov v1 0xC0; mvi 0x300; str v1; mvi 0x300; plane 3; sprite v2 v2 1; pitch v0; audio; end",
+    ).unwrap()).unwrap();
+    while cpu.run(&mut memory, &mut screen, &mut keyboard, &mut timers).unwrap() != StepOutcome::Halted {}
+
+    assert_eq!(screen[0][0], 0b11);
+    assert_eq!(screen[1][0], 0b10);
+    assert_eq!(timers.pitch, 0x80);
+    assert_eq!(timers.pattern.unwrap()[..2], [0x80, 0xC0]);
+
+    screen.select_planes(2);
+    screen.clear();
+    assert_eq!(screen[0][0], 0b01);
+    assert_eq!(screen[1][0], 0b00);
+}
@@ -0,0 +1,631 @@
+#[cfg(test)]
+use crate::quirks::Quirks;
+use std::{collections::BTreeSet, fmt};
+
+use crate::{
+    assembler,
+    cpu::{CpuError, StepOutcome},
+    disassembler,
+    instruction::{self, Instruction},
+    memory::{Access, Watchpoint, MEMORY_SIZE},
+    symbols::SymbolTable,
+    undo::{UndoLog, DEFAULT_UNDO_STEPS},
+    Chip8, FRAME_RATE,
+};
+
+// Instructions shown after the program counter
+const UPCOMING: usize = 8;
+// Watch log lines shown
+const LOG_LINES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Memory(Watchpoint),
+    /// The register takes the value.
+    Register(Register, u16),
+}
+
+/// What `unwatch` removes: the watches on an address, or on a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watched {
+    Addr(u16),
+    Register(Register),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Executes one instruction.
+    Step,
+    /// Executes one instruction, or a whole subroutine if it is a `jsr`.
+    Next,
+    Continue,
+    /// Runs until the program counter reaches the address.
+    Until(u16),
+    /// Undoes the last instruction.
+    StepBack,
+    /// Undoes instructions until the program counter is at a breakpoint.
+    ReverseContinue,
+    Break(u16),
+    Delete(u16),
+    /// Pauses when the watch triggers, or only logs it if `log` is set.
+    Watch {
+        watch: Watch,
+        log: bool,
+    },
+    Unwatch(Watched),
+    Quit,
+}
+
+/// A command line that isn't a command, with the reason.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCommand(pub String);
+
+impl fmt::Display for InvalidCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCommand {}
+
+/// Pauses a running program at breakpoints and on request, and runs it one instruction at a
+/// time while paused. Addresses can be given as labels from the symbol table.
+pub struct Debugger {
+    symbols: SymbolTable,
+    breakpoints: BTreeSet<u16>,
+    paused: bool,
+    // Where `next` or `until` stops: the address, and for `next` the stack depth to return to
+    target: Option<(u16, Option<usize>)>,
+    // With whether they only log
+    watches: Vec<(Watch, bool)>,
+    log: Vec<String>,
+    undo: UndoLog,
+}
+
+impl Debugger {
+    /// A debugger that starts paused, before the first instruction.
+    pub fn new(symbols: SymbolTable) -> Debugger {
+        Debugger {
+            symbols,
+            breakpoints: BTreeSet::new(),
+            paused: true,
+            target: None,
+            watches: Vec::new(),
+            log: Vec::new(),
+            undo: UndoLog::new(DEFAULT_UNDO_STEPS),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.target = None;
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Forgets the instructions that can be undone, for when the machine changed some other
+    /// way, like loading a state.
+    pub fn forget_history(&mut self) {
+        self.undo.clear();
+    }
+
+    /// The watches that triggered, oldest first.
+    pub fn log(&self) -> &[String] {
+        &self.log
+    }
+
+    /// Parses a command line like `b loop`, `until 0x20a` or `step`.
+    pub fn parse(&self, line: &str) -> Result<Command, InvalidCommand> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let addr = || match words.get(1) {
+            Some(word) => self.addr(word),
+            None => Err(InvalidCommand(format!("`{}` expects an address", words[0]))),
+        };
+        match words.first().copied() {
+            Some("s" | "step") => Ok(Command::Step),
+            Some("n" | "next") => Ok(Command::Next),
+            Some("c" | "continue") => Ok(Command::Continue),
+            Some("u" | "until") => Ok(Command::Until(addr()?)),
+            Some("sb" | "step-back") => Ok(Command::StepBack),
+            Some("rc" | "reverse-continue") => Ok(Command::ReverseContinue),
+            Some("b" | "break") => Ok(Command::Break(addr()?)),
+            Some("d" | "delete") => Ok(Command::Delete(addr()?)),
+            Some(word @ ("w" | "watch" | "l" | "log")) => Ok(Command::Watch {
+                watch: self.watch(&words)?,
+                log: word.starts_with('l'),
+            }),
+            Some("unwatch") => {
+                let word = words
+                    .get(1)
+                    .ok_or_else(|| InvalidCommand("`unwatch` expects an address".to_string()))?;
+                match parse_register(word) {
+                    Some(reg) => Ok(Command::Unwatch(Watched::Register(reg))),
+                    None => Ok(Command::Unwatch(Watched::Addr(self.addr(word)?))),
+                }
+            }
+            Some("q" | "quit") => Ok(Command::Quit),
+            Some(word) => Err(InvalidCommand(format!("Unknown command `{}`", word))),
+            None => Err(InvalidCommand("Expected a command".to_string())),
+        }
+    }
+
+    // `watch vX=nn`, `watch i=nnn`, or `watch start[..end] [r|w|rw]`, writes by default
+    fn watch(&self, words: &[&str]) -> Result<Watch, InvalidCommand> {
+        let usage = || {
+            InvalidCommand(format!(
+                "Expected `{} <addr>[..<addr>] [r|w|rw]` or `{} <register>=<value>`",
+                words[0], words[0]
+            ))
+        };
+        let target = words.get(1).ok_or_else(usage)?;
+
+        if let Some((reg, value)) = target.split_once('=') {
+            let reg = parse_register(reg).ok_or_else(usage)?;
+            let value = self.addr(value)?;
+            if words.len() > 2 || (matches!(reg, Register::V(_)) && value > 0xFF) {
+                return Err(usage());
+            }
+            return Ok(Watch::Register(reg, value));
+        }
+
+        let (start, end) = match target.split_once("..") {
+            Some((start, end)) => (self.addr(start)?, self.addr(end)?),
+            None => (self.addr(target)?, self.addr(target)?),
+        };
+        let (reads, writes) = match words.get(2).copied() {
+            None | Some("w") => (false, true),
+            Some("r") => (true, false),
+            Some("rw") => (true, true),
+            Some(_) => return Err(usage()),
+        };
+        if start > end || words.len() > 3 {
+            return Err(usage());
+        }
+        Ok(Watch::Memory(Watchpoint {
+            start,
+            end,
+            reads,
+            writes,
+        }))
+    }
+
+    // A label, or a number as in the assembler
+    fn addr(&self, word: &str) -> Result<u16, InvalidCommand> {
+        if let Some(addr) = self.symbols.addr(word) {
+            return Ok(addr);
+        }
+        assembler::parse_u16(word)
+            .map_err(|_| InvalidCommand(format!("Unknown address `{}`", word)))
+    }
+
+    /// Carries out a command. Step and next run right away, continue and until resume the
+    /// program, to be run with `step`.
+    pub fn command(
+        &mut self,
+        command: Command,
+        chip8: &mut Chip8,
+    ) -> Result<StepOutcome, CpuError> {
+        match command {
+            Command::Step => return self.watched_step(chip8).0,
+            Command::Next => {
+                let pc = chip8.cpu().pc();
+                // Past the end of memory, the step reports the error
+                let opcode = (pc + 1 < MEMORY_SIZE).then(|| chip8.memory().get_u16(pc));
+                if let Some(Ok(Instruction::Call(_))) = opcode.map(instruction::decode) {
+                    let depth = chip8.cpu().stack_pointer();
+                    self.target = Some(((pc as u16).wrapping_add(2), Some(depth)));
+                    self.paused = false;
+                    return Ok(StepOutcome::Continue);
+                }
+                return self.watched_step(chip8).0;
+            }
+            Command::Continue => self.paused = false,
+            Command::Until(addr) => {
+                self.target = Some((addr, None));
+                self.paused = false;
+            }
+            Command::StepBack => {
+                chip8.undo(&mut self.undo);
+            }
+            Command::ReverseContinue => {
+                while chip8.undo(&mut self.undo) {
+                    if self.breakpoints.contains(&(chip8.cpu().pc() as u16)) {
+                        break;
+                    }
+                }
+            }
+            Command::Break(addr) => {
+                self.breakpoints.insert(addr);
+            }
+            Command::Delete(addr) => {
+                self.breakpoints.remove(&addr);
+            }
+            Command::Watch { watch, log } => {
+                if let Watch::Memory(watchpoint) = watch {
+                    chip8.memory_mut().watch(watchpoint);
+                }
+                self.watches.push((watch, log));
+            }
+            Command::Unwatch(Watched::Addr(addr)) => {
+                chip8.memory_mut().unwatch(addr);
+                self.watches.retain(|(watch, _)| match watch {
+                    Watch::Memory(w) => !(w.start..=w.end).contains(&addr),
+                    Watch::Register(..) => true,
+                });
+            }
+            Command::Unwatch(Watched::Register(reg)) => {
+                self.watches
+                    .retain(|(watch, _)| !matches!(watch, Watch::Register(r, _) if *r == reg));
+            }
+            Command::Quit => return Ok(StepOutcome::Halted),
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    /// Executes a frame of the running program like `Chip8::run_frame`, one `step` at a time.
+    /// Stops early if the program pauses, leaving the rest of the frame for later.
+    pub fn run_frame(&mut self, chip8: &mut Chip8) -> Result<StepOutcome, CpuError> {
+        let instructions = (chip8.clock_speed() / FRAME_RATE).max(1);
+        // A frame cut short by a pause goes on where it stopped, instead of running in full
+        for _ in chip8.timers().frame_instructions()..instructions {
+            match self.step(chip8)? {
+                StepOutcome::Continue if self.paused => return Ok(StepOutcome::Continue),
+                StepOutcome::Continue => {}
+                StepOutcome::Halted => return Ok(StepOutcome::Halted),
+                StepOutcome::WaitForVblank => break,
+            }
+        }
+        chip8.end_frame();
+        Ok(StepOutcome::Continue)
+    }
+
+    /// Executes one instruction of the running program, and pauses if the next one is at a
+    /// breakpoint or where `next` or `until` stops.
+    pub fn step(&mut self, chip8: &mut Chip8) -> Result<StepOutcome, CpuError> {
+        let (outcome, watched) = self.watched_step(chip8);
+        let pc = chip8.cpu().pc() as u16;
+        let reached = match self.target {
+            Some((addr, Some(depth))) => pc == addr && chip8.cpu().stack_pointer() == depth,
+            Some((addr, None)) => pc == addr,
+            None => false,
+        };
+        if reached || watched || self.breakpoints.contains(&pc) || outcome.is_err() {
+            self.pause();
+        }
+        outcome
+    }
+
+    // Executes one instruction and logs the watches it triggers. Also returns whether one of
+    // them should pause the program.
+    fn watched_step(&mut self, chip8: &mut Chip8) -> (Result<StepOutcome, CpuError>, bool) {
+        let pc = chip8.cpu().pc();
+        let before: Vec<u16> = self
+            .watches
+            .iter()
+            .map(|(watch, _)| match watch {
+                Watch::Register(reg, _) => register(chip8, *reg),
+                Watch::Memory(_) => 0,
+            })
+            .collect();
+        let outcome = chip8.step_undoable(&mut self.undo);
+
+        let mut pause = false;
+        let hits = chip8.memory_mut().take_hits();
+        for ((watch, log), before) in self.watches.iter().zip(before) {
+            let mut triggered = false;
+            match watch {
+                Watch::Memory(watchpoint) => {
+                    for hit in hits.iter().filter(|h| watchpoint.matches(h.addr, h.access)) {
+                        let access = match hit.access {
+                            Access::Read => "read",
+                            Access::Write => "write",
+                        };
+                        self.log.push(format!(
+                            "{:03x}: {} {:03x} = {:02x}",
+                            pc, access, hit.addr, hit.value
+                        ));
+                        triggered = true;
+                    }
+                }
+                Watch::Register(reg, value) => {
+                    if register(chip8, *reg) == *value && before != *value {
+                        let name = match reg {
+                            Register::V(x) => format!("v{:X}", x),
+                            Register::I => "i".to_string(),
+                        };
+                        self.log
+                            .push(format!("{:03x}: {} = {:02x}", pc, name, value));
+                        triggered = true;
+                    }
+                }
+            }
+            pause |= triggered && !log;
+        }
+        (outcome, pause)
+    }
+
+    /// The registers, the stack and the upcoming instructions, one line each.
+    pub fn pane(&self, chip8: &Chip8) -> Vec<String> {
+        let cpu = chip8.cpu();
+        let memory = chip8.memory();
+        let mut lines = Vec::new();
+
+        let mut pc = format!("pc {:03x}", cpu.pc());
+        if let Some(name) = self.symbols.describe(cpu.pc() as u16) {
+            pc.push_str(&format!(" ({})", name));
+        }
+        lines.push(pc);
+        lines.push(format!("i  {:03x}", cpu.i()));
+        for (n, values) in cpu.v().chunks(4).enumerate() {
+            let regs: Vec<_> = values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| format!("v{:X} {:02x}", n * 4 + i, v))
+                .collect();
+            lines.push(regs.join("  "));
+        }
+        let stack: Vec<_> = (0..cpu.stack_pointer())
+            .map(|sp| format!("{:03x}", memory.get_stack_addr(sp)))
+            .collect();
+        lines.push(format!("stack {}", stack.join(" ")).trim_end().to_string());
+        lines.push(String::new());
+
+        let mut addr = cpu.pc();
+        for _ in 0..UPCOMING {
+            if addr >= MEMORY_SIZE {
+                break;
+            }
+            let bytes: Vec<u8> = (addr..(addr + 4).min(MEMORY_SIZE))
+                .map(|a| memory[a])
+                .collect();
+            let (text, size) = disassembler::disassemble_instruction(&bytes);
+            let marker = match (addr == cpu.pc(), self.breakpoints.contains(&(addr as u16))) {
+                (true, _) => '>',
+                (false, true) => '*',
+                (false, false) => ' ',
+            };
+            lines.push(format!("{} {:03x}  {}", marker, addr, text));
+            addr += size;
+        }
+
+        lines.push(String::new());
+        let skip = self.log.len().saturating_sub(LOG_LINES);
+        lines.extend(self.log[skip..].iter().cloned());
+        lines
+    }
+}
+
+fn register(chip8: &Chip8, reg: Register) -> u16 {
+    match reg {
+        Register::V(x) => chip8.cpu().v()[x as usize] as u16,
+        Register::I => chip8.cpu().i(),
+    }
+}
+
+// `v0` to `vF`, or `i`
+fn parse_register(word: &str) -> Option<Register> {
+    if word.eq_ignore_ascii_case("i") {
+        return Some(Register::I);
+    }
+    let digit = word.strip_prefix(['v', 'V'])?;
+    match u8::from_str_radix(digit, 16) {
+        Ok(x) if digit.len() == 1 => Some(Register::V(x)),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_commands() {
+    let mut symbols = SymbolTable::new();
+    symbols.insert(0x20a, "sub");
+    let debugger = Debugger::new(symbols);
+
+    assert_eq!(debugger.parse("s"), Ok(Command::Step));
+    assert_eq!(debugger.parse("break sub"), Ok(Command::Break(0x20a)));
+    assert_eq!(debugger.parse("u 0x204"), Ok(Command::Until(0x204)));
+    assert_eq!(
+        debugger.parse("b nowhere"),
+        Err(InvalidCommand("Unknown address `nowhere`".to_string()))
+    );
+    assert_eq!(
+        debugger.parse("until"),
+        Err(InvalidCommand("`until` expects an address".to_string()))
+    );
+}
+
+#[test]
+fn test_step_and_breakpoints() {
+    let assembly = assembler::assemble_named(
+        "<input>",
+        "mov v0 1; jsr sub; mov v1 2; add v0 1; end; sub: add v2 1; rts",
+    )
+    .unwrap();
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&assembly.code).unwrap();
+    let mut debugger = Debugger::new(assembly.symbols);
+    assert!(debugger.is_paused());
+
+    // Step over the subroutine, which still runs
+    debugger.command(Command::Step, &mut chip8).unwrap();
+    debugger.command(Command::Next, &mut chip8).unwrap();
+    while !debugger.is_paused() {
+        debugger.step(&mut chip8).unwrap();
+    }
+    assert_eq!(chip8.cpu().pc(), 0x204);
+    assert_eq!(chip8.cpu().v()[2], 1);
+
+    let line = debugger.parse("b 0x206").unwrap();
+    debugger.command(line, &mut chip8).unwrap();
+    debugger.command(Command::Continue, &mut chip8).unwrap();
+    debugger.step(&mut chip8).unwrap();
+    assert!(debugger.is_paused());
+    assert_eq!(chip8.cpu().pc(), 0x206);
+
+    let pane = debugger.pane(&chip8);
+    assert_eq!(pane[0], "pc 206");
+    assert_eq!(pane[2], "v0 01  v1 02  v2 01  v3 00");
+    assert_eq!(pane[8], "> 206  add v0 0x01");
+    assert_eq!(pane[9], "  208  end");
+    assert_eq!(pane[10], "  20a  add v2 0x01");
+
+    // `next` over a call at the end of memory, and past the end
+    let at_end = |last: [u8; 2]| {
+        let mut rom = [0x60, 0x00].repeat((MEMORY_SIZE - 0x202) / 2);
+        rom.extend(last);
+        let mut chip8 = Chip8::new(Quirks::xochip());
+        chip8.load_rom(&rom).unwrap();
+        chip8.set_clock_speed((rom.len() as u64 / 2 - 1) * FRAME_RATE);
+        chip8.run_frame().unwrap();
+        assert_eq!(chip8.cpu().pc(), 0xFFFE);
+        chip8
+    };
+    let mut chip8 = at_end([0x22, 0x00]);
+    let mut debugger = Debugger::new(SymbolTable::new());
+    debugger.command(Command::Next, &mut chip8).unwrap();
+    assert!(!debugger.is_paused());
+    debugger.step(&mut chip8).unwrap();
+    assert_eq!(chip8.cpu().pc(), 0x200);
+
+    let mut chip8 = at_end([0x60, 0x00]);
+    debugger.command(Command::Next, &mut chip8).unwrap();
+    assert_eq!(
+        debugger.command(Command::Next, &mut chip8),
+        Err(CpuError::MemoryOutOfBounds { addr: 0x10000 })
+    );
+}
+
+#[test]
+fn test_run_frame() {
+    let assembly =
+        assembler::assemble_named("<input>", "add v0 1; add v0 1; add v0 1; end").unwrap();
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&assembly.code).unwrap();
+    let mut debugger = Debugger::new(assembly.symbols);
+
+    // A breakpoint stops the frame
+    debugger.command(Command::Break(0x204), &mut chip8).unwrap();
+    debugger.command(Command::Continue, &mut chip8).unwrap();
+    assert_eq!(debugger.run_frame(&mut chip8), Ok(StepOutcome::Continue));
+    assert!(debugger.is_paused());
+    assert_eq!(chip8.cpu().v()[0], 2);
+
+    debugger.command(Command::Continue, &mut chip8).unwrap();
+    assert_eq!(debugger.run_frame(&mut chip8), Ok(StepOutcome::Halted));
+    assert_eq!(chip8.cpu().v()[0], 3);
+
+    // A frame resumed after a pause runs what was left of it, and the timers tick once
+    let assembly =
+        assembler::assemble_named("<input>", "mov v0 5; sdelay v0; loop: add v1 1; jmp loop")
+            .unwrap();
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&assembly.code).unwrap();
+    let mut debugger = Debugger::new(assembly.symbols);
+    debugger.command(Command::Break(0x204), &mut chip8).unwrap();
+    debugger.command(Command::Continue, &mut chip8).unwrap();
+    debugger.run_frame(&mut chip8).unwrap();
+    assert!(debugger.is_paused());
+
+    debugger
+        .command(Command::Delete(0x204), &mut chip8)
+        .unwrap();
+    debugger.command(Command::Continue, &mut chip8).unwrap();
+    debugger.run_frame(&mut chip8).unwrap();
+    assert_eq!(chip8.cpu().v()[1], 5);
+    assert_eq!(chip8.timers().delay, 4);
+    debugger.run_frame(&mut chip8).unwrap();
+    assert_eq!(chip8.timers().delay, 3);
+}
+
+#[test]
+fn test_watches() {
+    let assembly = assembler::assemble_named(
+        "<input>",
+        "mvi data; mov v0 7; str v0; mov v3 5; add v3 5; mvi data; ldr v0; end; data: db 0",
+    )
+    .unwrap();
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&assembly.code).unwrap();
+    let mut debugger = Debugger::new(assembly.symbols);
+
+    for line in ["watch data", "log data r", "log v3=10"] {
+        let command = debugger.parse(line).unwrap();
+        debugger.command(command, &mut chip8).unwrap();
+    }
+    debugger.command(Command::Continue, &mut chip8).unwrap();
+    while !debugger.is_paused() {
+        debugger.step(&mut chip8).unwrap();
+    }
+    assert_eq!(chip8.cpu().pc(), 0x206);
+    assert_eq!(debugger.log(), ["204: write 210 = 07"]);
+
+    // Logging doesn't pause
+    debugger.command(Command::Continue, &mut chip8).unwrap();
+    while debugger.step(&mut chip8).unwrap() != StepOutcome::Halted {}
+    assert!(!debugger.is_paused());
+    assert_eq!(
+        debugger.log(),
+        ["204: write 210 = 07", "208: v3 = 0a", "20c: read 210 = 07"]
+    );
+
+    assert_eq!(
+        debugger.parse("unwatch v3"),
+        Ok(Command::Unwatch(Watched::Register(Register::V(3))))
+    );
+    assert!(debugger.parse("watch v3=0x100").is_err());
+    assert!(debugger.parse("watch 0x210..0x200").is_err());
+}
+
+#[test]
+fn test_reverse() {
+    let assembly = assembler::assemble_named(
+        "<input>",
+        "mov v0 1; loop: add v0 1; mvi 0x300; str v0; jmp loop",
+    )
+    .unwrap();
+    let mut chip8 = Chip8::new(Quirks::default());
+    chip8.load_rom(&assembly.code).unwrap();
+    let mut debugger = Debugger::new(assembly.symbols);
+
+    for _ in 0..9 {
+        debugger.command(Command::Step, &mut chip8).unwrap();
+    }
+    assert_eq!(chip8.cpu().v()[0], 3);
+    assert_eq!(chip8.memory()[0x300], 3);
+
+    debugger.command(Command::StepBack, &mut chip8).unwrap();
+    debugger.command(Command::StepBack, &mut chip8).unwrap();
+    assert_eq!(chip8.cpu().pc(), 0x206);
+    assert_eq!(chip8.memory()[0x300], 2);
+
+    let command = debugger.parse("b loop").unwrap();
+    debugger.command(command, &mut chip8).unwrap();
+    assert_eq!(debugger.parse("rc"), Ok(Command::ReverseContinue));
+    debugger
+        .command(Command::ReverseContinue, &mut chip8)
+        .unwrap();
+    assert_eq!(chip8.cpu().pc(), 0x202);
+    assert_eq!(chip8.cpu().v()[0], 2);
+
+    // Back to the start, where there is nothing left to undo
+    debugger
+        .command(Command::ReverseContinue, &mut chip8)
+        .unwrap();
+    debugger
+        .command(Command::ReverseContinue, &mut chip8)
+        .unwrap();
+    assert_eq!(chip8.cpu().pc(), 0x200);
+    assert_eq!(chip8.cpu().v()[0], 0);
+    assert_eq!(chip8.memory()[0x300], 0);
+}
@@ -0,0 +1,200 @@
+#[cfg(test)]
+use crate::assembler;
+use std::collections::BTreeSet;
+
+use crate::{
+    instruction::{self, Instruction, Instruction::*},
+    memory::PROGRAM_START,
+};
+
+enum Item {
+    // The operand of F000 nnnn is kept next to the instruction
+    Code(Instruction, Option<u16>),
+    Data,
+}
+
+use Item::*;
+
+/// Turns a ROM back into source that `assembler::assemble` turns into the same bytes.
+/// Jump and call targets get labels, and words that aren't instructions are written as data.
+pub fn disassemble(rom: &[u8]) -> String {
+    let mut items = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let (item, size) = decode_at(rom, offset);
+        items.push((offset, size, item));
+        offset += size;
+    }
+
+    let starts: BTreeSet<u16> = items
+        .iter()
+        .map(|(offset, _, _)| (PROGRAM_START + offset) as u16)
+        .collect();
+    let labels: BTreeSet<u16> = items
+        .iter()
+        .filter_map(|(_, _, item)| match item {
+            Code(Jump(addr) | Call(addr) | JumpOffset(addr), _) => Some(*addr),
+            _ => None,
+        })
+        .filter(|addr| starts.contains(addr))
+        .collect();
+
+    let mut out = String::new();
+    for (offset, size, item) in items {
+        let addr = (PROGRAM_START + offset) as u16;
+        if labels.contains(&addr) {
+            out.push_str(&format!("{}:\n", label(addr)));
+        }
+
+        let bytes = &rom[offset..offset + size];
+        let text = item_text(&item, bytes, &labels);
+        let raw = bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        out.push_str(&format!("    {:<24}# {:03x}: {}\n", text, addr, raw));
+    }
+    out
+}
+
+/// The source of the instruction at the start of `bytes`, and its size in bytes.
+pub fn disassemble_instruction(bytes: &[u8]) -> (String, usize) {
+    let (item, size) = decode_at(bytes, 0);
+    (item_text(&item, &bytes[..size], &BTreeSet::new()), size)
+}
+
+fn item_text(item: &Item, bytes: &[u8], labels: &BTreeSet<u16>) -> String {
+    match item {
+        Code(instruction, long_addr) => mnemonic(instruction, *long_addr, labels),
+        Data => format!(
+            "db {}",
+            bytes
+                .iter()
+                .map(|b| format!("0x{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+    }
+}
+
+fn decode_at(rom: &[u8], offset: usize) -> (Item, usize) {
+    if offset + 1 >= rom.len() {
+        return (Data, rom.len() - offset);
+    }
+
+    let opcode = u16::from_be_bytes([rom[offset], rom[offset + 1]]);
+    match instruction::decode(opcode) {
+        Ok(SetILong) if offset + 3 < rom.len() => {
+            let addr = u16::from_be_bytes([rom[offset + 2], rom[offset + 3]]);
+            (Code(SetILong, Some(addr)), 4)
+        }
+        Ok(SetILong) | Err(_) => (Data, 2),
+        Ok(instruction) => (Code(instruction, None), 2),
+    }
+}
+
+fn label(addr: u16) -> String {
+    format!("label_{:03x}", addr)
+}
+
+fn mnemonic(instruction: &Instruction, long_addr: Option<u16>, labels: &BTreeSet<u16>) -> String {
+    let target = |addr: u16| {
+        if labels.contains(&addr) {
+            label(addr)
+        } else {
+            format!("0x{:03x}", addr)
+        }
+    };
+
+    match *instruction {
+        End => "end".to_string(),
+        ScrollDown(n) => format!("scd {}", n),
+        Clear => "cls".to_string(),
+        Return => "rts".to_string(),
+        ScrollRight => "scr".to_string(),
+        ScrollLeft => "scl".to_string(),
+        Exit => "exit".to_string(),
+        Lores => "low".to_string(),
+        Hires => "high".to_string(),
+        Jump(addr) => format!("jmp {}", target(addr)),
+        Call(addr) => format!("jsr {}", target(addr)),
+        SkipEqImm(x, nn) => format!("skeq v{:X} 0x{:02x}", x, nn),
+        SkipNeqImm(x, nn) => format!("skne v{:X} 0x{:02x}", x, nn),
+        SkipEqReg(x, y) => format!("skeq v{:X} v{:X}", x, y),
+        StoreRange(x, y) => format!("strr v{:X} v{:X}", x, y),
+        LoadRange(x, y) => format!("ldrr v{:X} v{:X}", x, y),
+        SetImm(x, nn) => format!("mov v{:X} 0x{:02x}", x, nn),
+        AddImm(x, nn) => format!("add v{:X} 0x{:02x}", x, nn),
+        Move(x, y) => format!("mov v{:X} v{:X}", x, y),
+        Or(x, y) => format!("or v{:X} v{:X}", x, y),
+        And(x, y) => format!("and v{:X} v{:X}", x, y),
+        Xor(x, y) => format!("xor v{:X} v{:X}", x, y),
+        Add(x, y) => format!("add v{:X} v{:X}", x, y),
+        Sub(x, y) => format!("sub v{:X} v{:X}", x, y),
+        ShiftRight(x, y) => format!("shr v{:X} v{:X}", x, y),
+        SubReverse(x, y) => format!("rsb v{:X} v{:X}", x, y),
+        ShiftLeft(x, y) => format!("shl v{:X} v{:X}", x, y),
+        SkipNeqReg(x, y) => format!("skne v{:X} v{:X}", x, y),
+        SetI(addr) => format!("mvi 0x{:03x}", addr),
+        JumpOffset(addr) => format!("jmi {}", target(addr)),
+        Rand(x, nn) => format!("rand v{:X} 0x{:02x}", x, nn),
+        Draw(x, y, 0) => format!("xsprite v{:X} v{:X}", x, y),
+        Draw(x, y, n) => format!("sprite v{:X} v{:X} {}", x, y, n),
+        SkipKey(x) => format!("skpr v{:X}", x),
+        SkipNotKey(x) => format!("skup v{:X}", x),
+        SetILong => format!("mvil 0x{:04x}", long_addr.unwrap_or(0)),
+        Plane(n) => format!("plane {}", n),
+        Audio => "audio".to_string(),
+        GetDelay(x) => format!("gdelay v{:X}", x),
+        WaitKey(x) => format!("key v{:X}", x),
+        SetDelay(x) => format!("sdelay v{:X}", x),
+        SetSound(x) => format!("ssound v{:X}", x),
+        AddI(x) => format!("adi v{:X}", x),
+        Font(x) => format!("font v{:X}", x),
+        BigFont(x) => format!("xfont v{:X}", x),
+        Bcd(x) => format!("bcd v{:X}", x),
+        Pitch(x) => format!("pitch v{:X}", x),
+        Store(x) => format!("str v{:X}", x),
+        Load(x) => format!("ldr v{:X}", x),
+        StoreFlags(x) => format!("sflags v{:X}", x),
+        LoadFlags(x) => format!("gflags v{:X}", x),
+    }
+}
+
+#[test]
+fn test_disassemble() {
+    let rom = assembler::assemble("mov v0 0x10; jsr 0x206; end; add v0 1; jmp 0x206").unwrap();
+    let source = disassemble(&rom);
+
+    assert!(source.contains("label_206:\n"));
+    assert!(source.contains("jsr label_206"));
+    assert!(source.contains("# 200: 60 10"));
+    assert_eq!(assembler::assemble(&source).unwrap(), rom);
+}
+
+#[test]
+fn test_disassemble_data() {
+    let rom = [0x00, 0xE0, 0x51, 0x21, 0xF0, 0x00, 0x12, 0x34, 0x90, 0x01, 0xF0, 0x00, 0xAB];
+    let source = disassemble(&rom);
+
+    assert!(source.contains("db 0x51 0x21"));
+    assert!(source.contains("mvil 0x1234"));
+    assert_eq!(assembler::assemble(&source).unwrap(), rom);
+}
+
+#[test]
+fn test_disassemble_instruction() {
+    assert_eq!(
+        disassemble_instruction(&[0x22, 0x10, 0x00]),
+        ("jsr 0x210".to_string(), 2)
+    );
+    assert_eq!(
+        disassemble_instruction(&[0xF0, 0x00, 0x12, 0x34]),
+        ("mvil 0x1234".to_string(), 4)
+    );
+    assert_eq!(
+        disassemble_instruction(&[0x51, 0x21]),
+        ("db 0x51 0x21".to_string(), 2)
+    );
+}
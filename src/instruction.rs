@@ -0,0 +1,266 @@
+use std::fmt;
+
+/// A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction. `x` and `y` are register numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0000, custom instruction that stops the program
+    End,
+    /// 00Cn
+    ScrollDown(u8),
+    /// 00E0
+    Clear,
+    /// 00EE
+    Return,
+    /// 00FB
+    ScrollRight,
+    /// 00FC
+    ScrollLeft,
+    /// 00FD
+    Exit,
+    /// 00FE
+    Lores,
+    /// 00FF
+    Hires,
+    /// 1nnn
+    Jump(u16),
+    /// 2nnn
+    Call(u16),
+    /// 3xnn
+    SkipEqImm(u8, u8),
+    /// 4xnn
+    SkipNeqImm(u8, u8),
+    /// 5xy0
+    SkipEqReg(u8, u8),
+    /// 5xy2
+    StoreRange(u8, u8),
+    /// 5xy3
+    LoadRange(u8, u8),
+    /// 6xnn
+    SetImm(u8, u8),
+    /// 7xnn
+    AddImm(u8, u8),
+    /// 8xy0
+    Move(u8, u8),
+    /// 8xy1
+    Or(u8, u8),
+    /// 8xy2
+    And(u8, u8),
+    /// 8xy3
+    Xor(u8, u8),
+    /// 8xy4
+    Add(u8, u8),
+    /// 8xy5
+    Sub(u8, u8),
+    /// 8xy6
+    ShiftRight(u8, u8),
+    /// 8xy7
+    SubReverse(u8, u8),
+    /// 8xyE
+    ShiftLeft(u8, u8),
+    /// 9xy0
+    SkipNeqReg(u8, u8),
+    /// Annn
+    SetI(u16),
+    /// Bnnn
+    JumpOffset(u16),
+    /// Cxnn
+    Rand(u8, u8),
+    /// Dxyn, n = 0 draws a 16x16 sprite
+    Draw(u8, u8, u8),
+    /// Ex9E
+    SkipKey(u8),
+    /// ExA1
+    SkipNotKey(u8),
+    /// F000 nnnn, the address is the word following the instruction
+    SetILong,
+    /// Fn01, n is the plane bitmask
+    Plane(u8),
+    /// F002
+    Audio,
+    /// Fx07
+    GetDelay(u8),
+    /// Fx0A
+    WaitKey(u8),
+    /// Fx15
+    SetDelay(u8),
+    /// Fx18
+    SetSound(u8),
+    /// Fx1E
+    AddI(u8),
+    /// Fx29
+    Font(u8),
+    /// Fx30
+    BigFont(u8),
+    /// Fx33
+    Bcd(u8),
+    /// Fx3A
+    Pitch(u8),
+    /// Fx55
+    Store(u8),
+    /// Fx65
+    Load(u8),
+    /// Fx75
+    StoreFlags(u8),
+    /// Fx85
+    LoadFlags(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownOpcode(pub u16);
+
+impl fmt::Display for UnknownOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown opcode {:04x}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownOpcode {}
+
+#[rustfmt::skip]
+pub fn decode(opcode: u16) -> Result<Instruction, UnknownOpcode> {
+    use Instruction::*;
+
+    let c = ((opcode & 0xF000) >> 12) as u8;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let d = (opcode & 0x000F) as u8;
+
+    let n = (opcode & 0x000F) as u8;
+    let nn = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
+
+    let instruction = match (c, x, y, d) {
+        (  0,   0,   0,   0) => End,
+        (  0,   0, 0xC,   _) => ScrollDown(n),
+        (  0,   0, 0xE,   0) => Clear,
+        (  0,   0, 0xE, 0xE) => Return,
+        (  0,   0, 0xF, 0xB) => ScrollRight,
+        (  0,   0, 0xF, 0xC) => ScrollLeft,
+        (  0,   0, 0xF, 0xD) => Exit,
+        (  0,   0, 0xF, 0xE) => Lores,
+        (  0,   0, 0xF, 0xF) => Hires,
+        (0x1,   _,   _,   _) => Jump(nnn),
+        (0x2,   _,   _,   _) => Call(nnn),
+        (0x3,   _,   _,   _) => SkipEqImm(x, nn),
+        (0x4,   _,   _,   _) => SkipNeqImm(x, nn),
+        (0x5,   _,   _,   0) => SkipEqReg(x, y),
+        (0x5,   _,   _,   2) => StoreRange(x, y),
+        (0x5,   _,   _,   3) => LoadRange(x, y),
+        (0x6,   _,   _,   _) => SetImm(x, nn),
+        (0x7,   _,   _,   _) => AddImm(x, nn),
+        (0x8,   _,   _, 0x0) => Move(x, y),
+        (0x8,   _,   _, 0x1) => Or(x, y),
+        (0x8,   _,   _, 0x2) => And(x, y),
+        (0x8,   _,   _, 0x3) => Xor(x, y),
+        (0x8,   _,   _, 0x4) => Add(x, y),
+        (0x8,   _,   _, 0x5) => Sub(x, y),
+        (0x8,   _,   _, 0x6) => ShiftRight(x, y),
+        (0x8,   _,   _, 0x7) => SubReverse(x, y),
+        (0x8,   _,   _, 0xE) => ShiftLeft(x, y),
+        (0x9,   _,   _,   0) => SkipNeqReg(x, y),
+        (0xA,   _,   _,   _) => SetI(nnn),
+        (0xB,   _,   _,   _) => JumpOffset(nnn),
+        (0xC,   _,   _,   _) => Rand(x, nn),
+        (0xD,   _,   _,   _) => Draw(x, y, n),
+        (0xE,   _, 0x9, 0xE) => SkipKey(x),
+        (0xE,   _, 0xA, 0x1) => SkipNotKey(x),
+        (0xF,   0, 0x0, 0x0) => SetILong,
+        (0xF,   _, 0x0, 0x1) => Plane(x),
+        (0xF,   0, 0x0, 0x2) => Audio,
+        (0xF,   _, 0x0, 0x7) => GetDelay(x),
+        (0xF,   _, 0x0, 0xA) => WaitKey(x),
+        (0xF,   _, 0x1, 0x5) => SetDelay(x),
+        (0xF,   _, 0x1, 0x8) => SetSound(x),
+        (0xF,   _, 0x1, 0xE) => AddI(x),
+        (0xF,   _, 0x2, 0x9) => Font(x),
+        (0xF,   _, 0x3, 0x0) => BigFont(x),
+        (0xF,   _, 0x3, 0x3) => Bcd(x),
+        (0xF,   _, 0x3, 0xA) => Pitch(x),
+        (0xF,   _, 0x5, 0x5) => Store(x),
+        (0xF,   _, 0x6, 0x5) => Load(x),
+        (0xF,   _, 0x7, 0x5) => StoreFlags(x),
+        (0xF,   _, 0x8, 0x5) => LoadFlags(x),
+        _ => return Err(UnknownOpcode(opcode)),
+    };
+    Ok(instruction)
+}
+
+pub fn encode(instruction: &Instruction) -> u16 {
+    use Instruction::*;
+
+    let x = |x: u8| (x as u16 & 0xF) << 8;
+    let xy = |x: u8, y: u8| (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4;
+    let xnn = |x: u8, nn: u8| (x as u16 & 0xF) << 8 | nn as u16;
+
+    match *instruction {
+        End => 0x0000,
+        ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+        Clear => 0x00E0,
+        Return => 0x00EE,
+        ScrollRight => 0x00FB,
+        ScrollLeft => 0x00FC,
+        Exit => 0x00FD,
+        Lores => 0x00FE,
+        Hires => 0x00FF,
+        Jump(nnn) => 0x1000 | (nnn & 0xFFF),
+        Call(nnn) => 0x2000 | (nnn & 0xFFF),
+        SkipEqImm(vx, nn) => 0x3000 | xnn(vx, nn),
+        SkipNeqImm(vx, nn) => 0x4000 | xnn(vx, nn),
+        SkipEqReg(vx, vy) => 0x5000 | xy(vx, vy),
+        StoreRange(vx, vy) => 0x5002 | xy(vx, vy),
+        LoadRange(vx, vy) => 0x5003 | xy(vx, vy),
+        SetImm(vx, nn) => 0x6000 | xnn(vx, nn),
+        AddImm(vx, nn) => 0x7000 | xnn(vx, nn),
+        Move(vx, vy) => 0x8000 | xy(vx, vy),
+        Or(vx, vy) => 0x8001 | xy(vx, vy),
+        And(vx, vy) => 0x8002 | xy(vx, vy),
+        Xor(vx, vy) => 0x8003 | xy(vx, vy),
+        Add(vx, vy) => 0x8004 | xy(vx, vy),
+        Sub(vx, vy) => 0x8005 | xy(vx, vy),
+        ShiftRight(vx, vy) => 0x8006 | xy(vx, vy),
+        SubReverse(vx, vy) => 0x8007 | xy(vx, vy),
+        ShiftLeft(vx, vy) => 0x800E | xy(vx, vy),
+        SkipNeqReg(vx, vy) => 0x9000 | xy(vx, vy),
+        SetI(nnn) => 0xA000 | (nnn & 0xFFF),
+        JumpOffset(nnn) => 0xB000 | (nnn & 0xFFF),
+        Rand(vx, nn) => 0xC000 | xnn(vx, nn),
+        Draw(vx, vy, n) => 0xD000 | xy(vx, vy) | (n as u16 & 0xF),
+        SkipKey(vx) => 0xE09E | x(vx),
+        SkipNotKey(vx) => 0xE0A1 | x(vx),
+        SetILong => 0xF000,
+        Plane(n) => 0xF001 | x(n),
+        Audio => 0xF002,
+        GetDelay(vx) => 0xF007 | x(vx),
+        WaitKey(vx) => 0xF00A | x(vx),
+        SetDelay(vx) => 0xF015 | x(vx),
+        SetSound(vx) => 0xF018 | x(vx),
+        AddI(vx) => 0xF01E | x(vx),
+        Font(vx) => 0xF029 | x(vx),
+        BigFont(vx) => 0xF030 | x(vx),
+        Bcd(vx) => 0xF033 | x(vx),
+        Pitch(vx) => 0xF03A | x(vx),
+        Store(vx) => 0xF055 | x(vx),
+        Load(vx) => 0xF065 | x(vx),
+        StoreFlags(vx) => 0xF075 | x(vx),
+        LoadFlags(vx) => 0xF085 | x(vx),
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    for opcode in 0..=u16::MAX {
+        if let Ok(instruction) = decode(opcode) {
+            assert_eq!(encode(&instruction), opcode, "{:?}", instruction);
+        }
+    }
+}
+
+#[test]
+fn test_decode() {
+    assert_eq!(decode(0x00E0), Ok(Instruction::Clear));
+    assert_eq!(decode(0x8AB4), Ok(Instruction::Add(0xA, 0xB)));
+    assert_eq!(decode(0xD120), Ok(Instruction::Draw(1, 2, 0)));
+    assert_eq!(decode(0xF000), Ok(Instruction::SetILong));
+    assert_eq!(decode(0x5121), Err(UnknownOpcode(0x5121)));
+    assert_eq!(decode(0x9001), Err(UnknownOpcode(0x9001)));
+}
@@ -0,0 +1,20 @@
+pub mod assembler;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod fonts;
+pub mod instruction;
+pub mod keyboard;
+pub mod memory;
+pub mod octo;
+pub mod quirks;
+pub mod rewind;
+pub mod screen;
+pub mod state;
+pub mod symbols;
+pub mod timers;
+pub mod undo;
+
+mod chip8;
+
+pub use chip8::{Chip8, DEFAULT_CLOCK_SPEED, FRAME_RATE};
@@ -1,71 +1,278 @@
-use std::{env, io::Write, thread, time::Duration};
+use std::{env, io::Write, thread, time::{Duration, Instant}};
 
-use cpu::Cpu;
-use keyboard::Keyboard;
-use memory::Memory;
-use screen::Screen;
+use audio::Audio;
+use chip8emu::{
+    assembler::{self, Syntax},
+    disassembler,
+    octo,
+    cpu::{CpuError, StepOutcome},
+    debugger::Debugger,
+    quirks::{self, Quirks},
+    rewind::{Rewind, DEFAULT_REWIND_FRAMES},
+    symbols::SymbolTable,
+    Chip8, FRAME_RATE,
+};
+use terminal::{Display, Hotkey, Input, LineInput};
 
-mod assembler;
-
-mod cpu;
-mod fonts;
-mod keyboard;
-mod memory;
-mod screen;
-mod timers;
+mod audio;
+mod terminal;
 
 const USAGE: &str = r#"
 Usage: chip8 <rom file>
 Flags:
-    --assemble=<asm file>: create <rom file> from <asm file>
+    --assemble=<asm file>: create <rom file> from <asm file>, or from Octo source if it ends in .8o
+    --listing=<file>: with --assemble, also write the address and bytes of every line to <file>.
+        Not available for Octo sources
+    --syntax=<name>: with --assemble, the mnemonics of the source (native, cowgod)
+    --symbols=<file>: with --assemble, also write the address of every label to <file>.
+        Otherwise, load them from <file> to show label names
+    --disassemble=<rom file>: print the source of <rom file>, without running it
+    --quirks=<preset>: emulate the behaviour of an interpreter (vip, chip48, schip, xochip)
     --yshift: allows specifying a vY register for the 8xy6 and 8xyE instructions
     --clock-speed=n: allows specifying the clock speed (n) in Hz
+    --seed=n: seed the random numbers of Cxnn, so every run with the same keys is the same
+    --debug: start paused in the debugger. Esc pauses a running program. Commands:
+        s/step, n/next (steps over jsr), c/continue, u/until <addr>, sb/step-back,
+        rc/reverse-continue (back to the previous breakpoint), b/break <addr>,
+        d/delete <addr>, w/watch and l/log <addr>[..<addr>] [r|w|rw] or <vX|i>=<value>,
+        unwatch <addr|vX|i>, q/quit. Addresses can be labels from --symbols
+Keys:
+    F1-F4: save the machine to slots 1-4, in <rom file>.state1 to <rom file>.state4
+    F5-F8: load slots 1-4
+    F9, F10: go back in time a frame, or a second
+    Ctrl-C: quit
 "#;
 
-struct Chip8 {
-    cpu: Cpu,
-    memory: Memory,
-    screen: Screen,
-    keyboard: keyboard::Keyboard,
-    timers: timers::Timers,
-    clock_speed: u64,
+struct Frontend {
+    chip8: Chip8,
+    display: Display,
+    input: Input,
+    audio: Audio,
+    symbols: SymbolTable,
+    debugger: Option<Debugger>,
+    // The reply to the last debugger command
+    debug_message: String,
+    // Save states are written next to the ROM
+    rom_path: String,
+    // A state per frame
+    rewind: Rewind,
 }
 
-impl Chip8 {
-    pub fn new(flags: Vec<String>) -> Chip8 {
-        let mut chip8 = Chip8 {
-            cpu: Cpu::new(&flags),
-            memory: Memory::new(),
-            screen: Screen::new(true),
-            keyboard: Keyboard::new(),
-            timers: timers::Timers::new(),
-            clock_speed: 700,
+impl Frontend {
+    pub fn new(flags: Vec<String>, rom_path: &str, rom: &[u8]) -> Frontend {
+        let mut quirks = Quirks::default();
+        if let Some(preset) = flags.iter().find_map(|f| f.strip_prefix("--quirks=")) {
+            quirks = Quirks::from_preset(preset).unwrap_or_else(|| {
+                panic!(
+                    "Unknown quirks preset {}, expected one of {}",
+                    preset,
+                    quirks::PRESETS.join(", ")
+                )
+            });
+        }
+        if flags.iter().any(|s| s == "--yshift") {
+            quirks.shift_uses_vy = true;
+        }
+
+        let symbols = match flags.iter().find_map(|f| f.strip_prefix("--symbols=")) {
+            Some(path) => std::fs::read_to_string(path)
+                .expect("Failed to read symbol file")
+                .parse()
+                .unwrap_or_else(|err| panic!("Failed to load {}: {}", path, err)),
+            None => SymbolTable::new(),
         };
+
+        let mut chip8 = Chip8::new(quirks);
         if let Some(clock_speed_str) = flags.iter().find(|f| f.starts_with("--clock-speed=")) {
-            chip8.clock_speed = clock_speed_str
-                .strip_prefix("--clock-speed=")
-                .unwrap()
-                .parse()
-                .expect("Invalid clock speed");
+            chip8.set_clock_speed(
+                clock_speed_str
+                    .strip_prefix("--clock-speed=")
+                    .unwrap()
+                    .parse()
+                    .expect("Invalid clock speed"),
+            );
         }
-
+        if let Some(seed) = flags.iter().find_map(|f| f.strip_prefix("--seed=")) {
+            chip8.set_seed(seed.parse().expect("Invalid seed"));
+        }
+        // Before the display puts the terminal in raw mode, which exiting here would leave on
         chip8
+            .load_rom(rom)
+            .unwrap_or_else(|err| panic!("Failed to load {}: {}", rom_path, err));
+
+        let debugger = flags
+            .iter()
+            .any(|f| f == "--debug")
+            .then(|| Debugger::new(symbols.clone()));
+
+        Frontend {
+            chip8,
+            display: Display::new(),
+            input: Input::new(),
+            audio: Audio::new(),
+            symbols,
+            debugger,
+            debug_message: String::new(),
+            rom_path: rom_path.to_string(),
+            rewind: Rewind::new(DEFAULT_REWIND_FRAMES),
+        }
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        let frame = Duration::from_nanos(1_000_000_000 / FRAME_RATE);
+        let mut deadline = Instant::now();
         loop {
-            thread::sleep(Duration::from_nanos(1_000_000_000 / self.clock_speed));
-
-            self.timers.update();
-            self.keyboard.update();
-            let cont = self
-                .cpu
-                .run(&mut self.memory, &mut self.screen, &mut self.keyboard, &mut self.timers);
-            if !cont {
-                break;
+            // Sleep until the deadline rather than for a frame, so the time spent running and
+            // drawing and the sleeps that oversleep don't add up
+            deadline += frame;
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            } else if now - deadline > frame {
+                // Too far behind to catch up, like after the terminal was suspended
+                deadline = now;
+            }
+
+            if let Some(debugger) = &mut self.debugger {
+                if self.input.take_pause_request() {
+                    debugger.pause();
+                }
+                if debugger.is_paused() {
+                    if !self.debug_prompt() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            }
+
+            if !self.input.update(self.chip8.keyboard_mut()) {
+                return Ok(());
+            }
+            if let Some(hotkey) = self.input.take_hotkey() {
+                self.use_hotkey(hotkey);
+            }
+            let outcome = match &mut self.debugger {
+                // The debugger pauses on errors, so the crash can be looked at and stepped back
+                Some(debugger) => match debugger.run_frame(&mut self.chip8) {
+                    Ok(outcome) => outcome,
+                    Err(err) => {
+                        self.debug_message = format!("Crashed: {}", err);
+                        StepOutcome::Continue
+                    }
+                },
+                None => self.chip8.run_frame()?,
+            };
+            if outcome == StepOutcome::Halted {
+                return Ok(());
+            }
+            self.rewind.push(self.chip8.save_state());
+
+            self.audio.update(self.chip8.timers());
+            self.display.draw(self.chip8.screen_mut());
+        }
+    }
+
+    fn use_hotkey(&mut self, hotkey: Hotkey) {
+        let status = match hotkey {
+            Hotkey::Save(slot) => {
+                let path = format!("{}.state{}", self.rom_path, slot);
+                match std::fs::write(&path, self.chip8.save_state()) {
+                    Ok(()) => format!("Saved slot {}", slot),
+                    Err(err) => format!("Failed to save {}: {}", path, err),
+                }
+            }
+            Hotkey::Load(slot) => {
+                let path = format!("{}.state{}", self.rom_path, slot);
+                let loaded = std::fs::read(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|state| self.chip8.load_state(&state).map_err(|e| e.to_string()));
+                match loaded {
+                    Ok(()) => format!("Loaded slot {}", slot),
+                    Err(err) => format!("Failed to load {}: {}", path, err),
+                }
+            }
+            Hotkey::Rewind(frames) => {
+                let mut state = None;
+                for _ in 0..frames {
+                    match self.rewind.back() {
+                        Some(previous) => state = Some(previous.to_vec()),
+                        None => break,
+                    }
+                }
+                match state {
+                    Some(state) => {
+                        self.chip8
+                            .load_state(&state)
+                            .expect("rewind states come from save_state");
+                        format!("Rewound, {} frames left", self.rewind.len() - 1)
+                    }
+                    None => "Nothing to rewind".to_string(),
+                }
+            }
+        };
+        // Undoing instructions from before the machine changed would mix both
+        if let Some(debugger) = &mut self.debugger {
+            if matches!(hotkey, Hotkey::Load(_) | Hotkey::Rewind(_)) {
+                debugger.forget_history();
             }
+        }
+        self.display.draw_status(&status);
+    }
+
+    // Reads and carries out debugger commands while paused. Returns false once the user quits.
+    fn debug_prompt(&mut self) -> bool {
+        let Some(debugger) = &mut self.debugger else {
+            return true;
+        };
+        match self.input.read_line() {
+            LineInput::Editing => {}
+            LineInput::Quit => return false,
+            LineInput::Entered(line) => {
+                self.debug_message.clear();
+                match debugger.parse(&line) {
+                    Ok(command) => match debugger.command(command, &mut self.chip8) {
+                        Ok(StepOutcome::Halted) => return false,
+                        Ok(_) => {}
+                        Err(err) => self.debug_message = format!("Crashed: {}", err),
+                    },
+                    Err(err) => self.debug_message = err.to_string(),
+                }
+            }
+        }
+
+        let mut lines = debugger.pane(&self.chip8);
+        let breakpoints: Vec<_> = debugger
+            .breakpoints()
+            .map(|addr| format!("{:03x}", addr))
+            .collect();
+        lines.push(String::new());
+        lines.push(format!("breakpoints {}", breakpoints.join(" ")));
+        lines.push(self.debug_message.clone());
+        if debugger.is_paused() {
+            lines.push(format!("(debug) {}", self.input.line()));
+        } else {
+            lines.push("running, Esc pauses".to_string());
+        }
+        self.display.draw_pane(&lines);
+        self.display.draw(self.chip8.screen_mut());
+        true
+    }
 
-            self.screen.draw();
+    fn print_crash_report(&mut self, err: CpuError) {
+        self.display.suspend();
+
+        let cpu = self.chip8.cpu();
+        eprintln!("Crashed: {}", err);
+        eprint!("pc: {:03x}", cpu.pc());
+        if let Some(name) = self.symbols.describe(cpu.pc() as u16) {
+            eprint!(" ({})", name);
+        }
+        eprintln!("  i: {:03x}  sp: {}", cpu.i(), cpu.stack_pointer());
+        for (i, v) in cpu.v().iter().enumerate() {
+            eprint!("v{:X}: {:02x}  ", i, v);
+            if i % 8 == 7 {
+                eprintln!();
+            }
         }
     }
 }
@@ -84,6 +291,12 @@ fn main() {
         .cloned()
         .collect();
 
+    if let Some(rom_path) = flags.iter().find_map(|f| f.strip_prefix("--disassemble=")) {
+        let rom = std::fs::read(rom_path).expect("Failed to read ROM file");
+        print!("{}", disassembler::disassemble(&rom));
+        return;
+    }
+
     if flags.iter().any(|f| f.starts_with("--assemble=")) {
         let asm_path = flags
             .iter()
@@ -96,11 +309,46 @@ fn main() {
             .find(|str| !str.starts_with("-"))
             .expect("No ROM file specified");
         let asm_str = std::fs::read_to_string(asm_path).expect("Failed to read ASM file");
-        let bytecode = assembler::assemble(&asm_str);
+        let mut syntax = Syntax::default();
+        if let Some(name) = flags.iter().find_map(|f| f.strip_prefix("--syntax=")) {
+            syntax = Syntax::from_name(name).unwrap_or_else(|| {
+                panic!(
+                    "Unknown syntax {}, expected one of {}",
+                    name,
+                    assembler::SYNTAXES.join(", ")
+                )
+            });
+        }
+        // Octo sources use the .8o extension
+        let octo = asm_path.ends_with(".8o");
+        if octo && flags.iter().any(|f| f.starts_with("--listing=")) {
+            eprintln!("--listing is not available for Octo sources");
+            std::process::exit(1);
+        }
+        let assembly = if octo {
+            octo::assemble_octo_named(asm_path, &asm_str)
+        } else {
+            assembler::assemble_with_syntax(asm_path, &asm_str, syntax)
+        };
+        let assembly = assembly.unwrap_or_else(|errors| {
+            for error in &errors {
+                eprintln!("{}\n", error);
+            }
+            eprintln!("Failed to assemble {}: {} errors", asm_path, errors.len());
+            std::process::exit(1);
+        });
 
         println!("Writing ROM to {}", rom_path);
         let mut file = std::fs::File::create(rom_path).expect("Failed to create ROM file");
-        file.write_all(&bytecode).expect("Failed to write ROM file");
+        file.write_all(&assembly.code).expect("Failed to write ROM file");
+
+        if let Some(path) = flags.iter().find_map(|f| f.strip_prefix("--listing=")) {
+            std::fs::write(path, &assembly.listing).expect("Failed to write listing file");
+        }
+        if let Some(path) = flags.iter().find_map(|f| f.strip_prefix("--symbols=")) {
+            std::fs::write(path, assembly.symbols.to_string())
+                .expect("Failed to write symbol file");
+        }
         return;
     }
 
@@ -108,13 +356,29 @@ fn main() {
         .iter()
         .find(|str| !str.starts_with("-"))
         .expect("No ROM file specified");
-    let mut chip8 = Chip8::new(flags);
     let rom = std::fs::read(rom_path).expect("Failed to read ROM file");
 
     println!("Loading ROM {}", rom_path);
 
-    chip8.memory.load_fonts(fonts::FONT);
-    chip8.memory.load_program(&rom);
+    let mut frontend = Frontend::new(flags, rom_path, &rom);
+
+    // SUPER-CHIP RPL user flags persist between runs, next to the ROM
+    let rpl_path = format!("{}.rpl", rom_path);
+    if let Ok(rpl) = std::fs::read(&rpl_path) {
+        if let Ok(rpl) = rpl.try_into() {
+            frontend.chip8.set_rpl_flags(rpl);
+        }
+    }
 
-    chip8.run();
+    let initial_rpl = *frontend.chip8.cpu().rpl_flags();
+    let result = frontend.run();
+    if *frontend.chip8.cpu().rpl_flags() != initial_rpl {
+        std::fs::write(&rpl_path, frontend.chip8.cpu().rpl_flags())
+            .expect("Failed to write RPL flags file");
+    }
+
+    if let Err(err) = result {
+        frontend.print_crash_report(err);
+        std::process::exit(1);
+    }
 }
@@ -1,34 +1,169 @@
+use std::fmt;
 use std::ops::{Index, IndexMut};
 
+use crate::state::{StateError, StateReader, StateWriter};
+
 const STACK_START: usize = 0x000;
 pub const STACK_SIZE: usize = 16;
 
 pub const FONT_START: usize = 0x050;
+pub const BIG_FONT_START: usize = 0x0A0;
 pub const PROGRAM_START: usize = 0x200;
+// XO-CHIP address space, CHIP-8 programs only use the first 4 KiB
+pub const MEMORY_SIZE: usize = 0x10000;
+pub const CHIP8_MEMORY_SIZE: usize = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Addresses from `start` to `end`, included, where the program's reads or writes are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub reads: bool,
+    pub writes: bool,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, addr: usize, access: Access) -> bool {
+        let watched = match access {
+            Access::Read => self.reads,
+            Access::Write => self.writes,
+        };
+        watched && (self.start as usize..=self.end as usize).contains(&addr)
+    }
+}
+
+/// A read or write caught by a watchpoint. `value` is the byte read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: usize,
+    pub access: Access,
+    pub value: u8,
+}
+
+/// The program doesn't fit between `PROGRAM_START` and the end of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for RomTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ROM is {} bytes, but only {} fit in memory", self.size, self.max)
+    }
+}
+
+impl std::error::Error for RomTooLarge {}
 
-pub struct Memory([u8; 0x1000]);
+/// Indexing reads and writes memory directly. The program's own data accesses go through
+/// `read` and `write`, so watchpoints see them.
+pub struct Memory {
+    bytes: [u8; MEMORY_SIZE],
+    watchpoints: Vec<Watchpoint>,
+    hits: Vec<WatchHit>,
+    // Old values of the bytes the program writes, while an undoable step runs
+    journal: Option<Vec<(usize, u8)>>,
+}
 
 impl Index<usize> for Memory {
     type Output = u8;
 
     fn index(&self, index: usize) -> &u8 {
-        &self.0[index]
+        &self.bytes[index]
     }
 }
 
 impl IndexMut<usize> for Memory {
     fn index_mut(&mut self, index: usize) -> &mut u8 {
-        &mut self.0[index]
+        &mut self.bytes[index]
     }
 }
 
 impl Memory {
     pub fn new() -> Memory {
-        Memory([0; 0x1000])
+        Memory {
+            bytes: [0; MEMORY_SIZE],
+            watchpoints: Vec::new(),
+            hits: Vec::new(),
+            journal: None,
+        }
+    }
+
+    // Watchpoints belong to the debugger, not the machine, and aren't saved
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.bytes);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.bytes.copy_from_slice(state.bytes(MEMORY_SIZE)?);
+        Ok(())
+    }
+
+    pub fn watch(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Removes the watchpoints that include `addr`.
+    pub fn unwatch(&mut self, addr: u16) {
+        self.watchpoints
+            .retain(|w| !(w.start..=w.end).contains(&addr));
+    }
+
+    /// The watched accesses since the last call.
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    /// A byte of data read by the program.
+    pub fn read(&mut self, addr: usize) -> u8 {
+        let value = self.bytes[addr];
+        self.record(addr, Access::Read, value);
+        value
+    }
+
+    /// A byte of data written by the program.
+    pub fn write(&mut self, addr: usize, value: u8) {
+        self.log_old_value(addr);
+        self.bytes[addr] = value;
+        self.record(addr, Access::Write, value);
+    }
+
+    fn record(&mut self, addr: usize, access: Access, value: u8) {
+        if self.watchpoints.iter().any(|w| w.matches(addr, access)) {
+            self.hits.push(WatchHit {
+                addr,
+                access,
+                value,
+            });
+        }
+    }
+
+    pub(crate) fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /// The old values of the bytes written since `start_journal`.
+    pub(crate) fn take_journal(&mut self) -> Vec<(usize, u8)> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    fn log_old_value(&mut self, addr: usize) {
+        if let Some(journal) = &mut self.journal {
+            journal.push((addr, self.bytes[addr]));
+        }
     }
 
     pub fn set_stack_addr(&mut self, stack_pointer: usize, value: u16) {
-        self.set_u16(STACK_START + stack_pointer * 2, value);
+        let addr = STACK_START + stack_pointer * 2;
+        self.log_old_value(addr);
+        self.log_old_value(addr + 1);
+        self.set_u16(addr, value);
     }
 
     pub fn get_stack_addr(&self, stack_pointer: usize) -> u16 {
@@ -47,10 +182,13 @@ impl Memory {
         self[addr + 1] = value as u8;
     }
 
-    pub fn load_program(&mut self, program: &[u8]) {
-        for (i, byte) in program.iter().enumerate() {
-            self[i + PROGRAM_START] = *byte;
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), RomTooLarge> {
+        let max = MEMORY_SIZE - PROGRAM_START;
+        if program.len() > max {
+            return Err(RomTooLarge { size: program.len(), max });
         }
+        self.bytes[PROGRAM_START..PROGRAM_START + program.len()].copy_from_slice(program);
+        Ok(())
     }
 
     pub fn load_fonts(&mut self, fonts: &[u8]) {
@@ -58,4 +196,56 @@ impl Memory {
             self[i + FONT_START] = *byte;
         }
     }
+
+    pub fn load_big_fonts(&mut self, fonts: &[u8]) {
+        for (i, byte) in fonts.iter().enumerate() {
+            self[i + BIG_FONT_START] = *byte;
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+#[test]
+fn test_watchpoints() {
+    let mut memory = Memory::new();
+    memory.watch(Watchpoint {
+        start: 0x300,
+        end: 0x30f,
+        reads: false,
+        writes: true,
+    });
+
+    memory.write(0x2ff, 1);
+    memory.write(0x305, 2);
+    memory.read(0x305);
+    memory[0x306] = 3;
+    assert_eq!(
+        memory.take_hits(),
+        [WatchHit {
+            addr: 0x305,
+            access: Access::Write,
+            value: 2
+        }]
+    );
+    assert_eq!(memory.take_hits(), []);
+
+    memory.unwatch(0x30f);
+    memory.write(0x305, 4);
+    assert_eq!(memory.take_hits(), []);
+}
+
+#[test]
+fn test_load_program() {
+    let mut memory = Memory::new();
+    memory.load_program(&[1, 2, 3]).unwrap();
+    assert_eq!(memory.bytes[PROGRAM_START..PROGRAM_START + 4], [1, 2, 3, 0]);
+    assert_eq!(
+        memory.load_program(&[0; MEMORY_SIZE]),
+        Err(RomTooLarge { size: MEMORY_SIZE, max: MEMORY_SIZE - PROGRAM_START })
+    );
 }
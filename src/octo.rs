@@ -0,0 +1,834 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    assembler::{AsmError, Assembly},
+    memory::{MEMORY_SIZE, PROGRAM_START},
+    symbols::SymbolTable,
+};
+
+// Most nested macro calls, so recursive macros stop
+const MAX_MACRO_DEPTH: usize = 256;
+// Most tokens macros expand to, so macros that use each other more than once don't take forever
+const MAX_EXPANDED_TOKENS: usize = 100_000;
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    /// 1-based
+    line: usize,
+    /// 1-based, counted in characters
+    column: usize,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// How to write a label into an instruction once it's defined
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    // The nnn field of an instruction
+    Addr,
+    // 6xnn with the high byte of the address, or-ed with a nibble, for :unpack
+    UnpackHigh(u8),
+    // 6xnn with the low byte of the address, for :unpack
+    UnpackLow,
+}
+
+struct Loop {
+    start: usize,
+    token: Token,
+    // `while` jumps out of the loop
+    breaks: Vec<usize>,
+}
+
+struct Compiler<'a> {
+    file: &'a str,
+    lines: Vec<&'a str>,
+    tokens: VecDeque<Token>,
+    last: Option<Token>,
+    rom: Vec<u8>,
+    here: usize,
+    // Whether 0x200 holds the jump to main
+    has_main: bool,
+    labels: HashMap<String, u16>,
+    // The labels in the order they are defined
+    symbols: SymbolTable,
+    constants: HashMap<String, i32>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    // For each macro being expanded, how many tokens are left once its body is done
+    expansions: Vec<usize>,
+    // Tokens expanded so far, empty bodies count as one
+    expanded_tokens: usize,
+    fixups: Vec<(usize, Token, Fixup)>,
+    // The jumps of `begin` and `else` waiting for their `else` or `end`
+    branches: Vec<(usize, Token)>,
+    loops: Vec<Loop>,
+}
+
+/// Assembles Octo source, reporting errors as coming from `<input>`.
+pub fn assemble_octo(source: &str) -> Result<Vec<u8>, Vec<AsmError>> {
+    assemble_octo_named("<input>", source).map(|assembly| assembly.code)
+}
+
+/// Assembles Octo source into the same bytes as the Octo reference compiler, for the CHIP-8 and
+/// SUPER-CHIP instructions. The listing of the result is empty.
+pub fn assemble_octo_named(file: &str, source: &str) -> Result<Assembly, Vec<AsmError>> {
+    let mut compiler = Compiler {
+        file,
+        lines: source.lines().collect(),
+        tokens: tokenize(source),
+        last: None,
+        rom: Vec::new(),
+        here: PROGRAM_START,
+        has_main: true,
+        labels: HashMap::new(),
+        symbols: SymbolTable::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        expansions: Vec::new(),
+        expanded_tokens: 0,
+        fixups: Vec::new(),
+        branches: Vec::new(),
+        loops: Vec::new(),
+    };
+
+    // Octo starts with a jump to main, dropped if main is right after it
+    compiler.inst(0x10, 0x00);
+    while let Some(token) = compiler.tokens.pop_front() {
+        compiler.last = Some(token.clone());
+        compiler.statement(token.clone()).map_err(|err| vec![err])?;
+        if compiler.here > MEMORY_SIZE {
+            let message = format!(
+                "`{}` goes past the end of memory at {:#x}",
+                token.text, MEMORY_SIZE
+            );
+            return Err(vec![compiler.error(&token, message)]);
+        }
+    }
+    compiler.finish()
+}
+
+// Whitespace separated tokens, without `#` comments
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (i, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap();
+        let mut start = None;
+        for (pos, c) in code.char_indices().chain([(code.len(), ' ')]) {
+            if !c.is_whitespace() {
+                start = start.or(Some(pos));
+            } else if let Some(start) = start.take() {
+                tokens.push_back(Token {
+                    text: code[start..pos].to_string(),
+                    line: i + 1,
+                    column: code[..start].chars().count() + 1,
+                });
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i32::from_str_radix(bin, 2).ok()?
+    } else {
+        digits.parse::<i32>().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+// The comparison that is true when `op` is false
+fn negate(op: &str) -> Option<&'static str> {
+    Some(match op {
+        "==" => "!=",
+        "!=" => "==",
+        "key" => "-key",
+        "-key" => "key",
+        ">" => "<=",
+        "<=" => ">",
+        "<" => ">=",
+        ">=" => "<",
+        _ => return None,
+    })
+}
+
+impl Compiler<'_> {
+    fn error(&self, token: &Token, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.to_string(),
+            line: token.line,
+            column: token.column,
+            len: token.text.chars().count(),
+            message: message.into(),
+            // An empty source has no line 1
+            source_line: self.lines.get(token.line - 1).unwrap_or(&"").to_string(),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = Some(token.clone());
+                Ok(token)
+            }
+            None => {
+                let last = self.last.clone().unwrap();
+                Err(self.error(&last, "Unexpected end of file"))
+            }
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(self.error(&token, format!("Expected `{}`", text)));
+        }
+        Ok(())
+    }
+
+    fn peek(&self, n: usize) -> Option<&str> {
+        self.tokens.get(n).map(|t| t.text.as_str())
+    }
+
+    fn emit(&mut self, byte: u8) {
+        let index = self.here - PROGRAM_START;
+        if index >= self.rom.len() {
+            self.rom.resize(index + 1, 0);
+        }
+        self.rom[index] = byte;
+        self.here += 1;
+    }
+
+    fn inst(&mut self, a: u8, b: u8) {
+        self.emit(a);
+        self.emit(b);
+    }
+
+    // Writes an address into the nnn field of the instruction at `addr`
+    fn patch(&mut self, addr: usize, target: u16) {
+        let index = addr - PROGRAM_START;
+        self.rom[index] = (self.rom[index] & 0xF0) | (target >> 8) as u8 & 0xF;
+        self.rom[index + 1] = target as u8;
+    }
+
+    fn register_of(&self, text: &str) -> Option<u8> {
+        if let Some(&reg) = self.aliases.get(text) {
+            return Some(reg);
+        }
+        let num = text.strip_prefix(['v', 'V'])?;
+        match u8::from_str_radix(num, 16) {
+            Ok(reg) if num.len() == 1 => Some(reg),
+            _ => None,
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.register_of(&token.text)
+            .ok_or_else(|| self.error(&token, "Expected a register"))
+    }
+
+    // A number, constant or label that is already defined
+    fn value_of(&self, token: &Token) -> Option<i32> {
+        parse_number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|&addr| addr as i32))
+    }
+
+    fn value(&mut self, min: i32, max: i32) -> Result<i32, AsmError> {
+        let token = self.next()?;
+        self.value_in(&token, min, max)
+    }
+
+    fn value_in(&self, token: &Token, min: i32, max: i32) -> Result<i32, AsmError> {
+        let value = self
+            .value_of(token)
+            .ok_or_else(|| self.error(token, format!("Unknown value `{}`", token.text)))?;
+        if value < min || value > max {
+            return Err(self.error(
+                token,
+                format!("{} is out of range, expected {} to {}", value, min, max),
+            ));
+        }
+        Ok(value)
+    }
+
+    // A byte, negative numbers are two's complement
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.byte_of(&token)
+    }
+
+    fn byte_of(&self, token: &Token) -> Result<u8, AsmError> {
+        Ok(self.value_in(token, -128, 255)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        Ok(self.value(0, 15)? as u8)
+    }
+
+    // An instruction with an nnn address, which can be a label defined later
+    fn addr_inst(&mut self, opcode: u8) -> Result<(), AsmError> {
+        let token = self.next()?;
+        let addr = match self.value_of(&token) {
+            Some(addr) if (0..=0xFFF).contains(&addr) => addr as u16,
+            Some(addr) => {
+                return Err(self.error(&token, format!("Address {:#x} is out of range", addr)))
+            }
+            None => {
+                self.fixups.push((self.here, token, Fixup::Addr));
+                0
+            }
+        };
+        self.inst(opcode << 4 | (addr >> 8) as u8, addr as u8);
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: &Token, addr: usize) -> Result<(), AsmError> {
+        if self.labels.insert(name.text.clone(), addr as u16).is_some() {
+            return Err(self.error(name, format!("`{}` is already defined", name.text)));
+        }
+        self.symbols.insert(addr as u16, &name.text);
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                if name.text == "main" && self.has_main && self.here == PROGRAM_START + 2 {
+                    self.has_main = false;
+                    self.here = PROGRAM_START;
+                    self.rom.clear();
+                }
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let reg = self.register()?;
+                self.aliases.insert(name.text, reg);
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.value(i32::MIN, i32::MAX)?;
+                self.constants.insert(name.text, value);
+            }
+            ":macro" => self.define_macro()?,
+            ":org" => self.here = self.value(PROGRAM_START as i32, 0xFFFF)? as usize,
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(byte);
+            }
+            ":unpack" => {
+                let nibble = self.nibble()?;
+                let label = self.next()?;
+                match self.value_of(&label) {
+                    Some(addr) => {
+                        self.inst(0x60, (nibble << 4) | (addr >> 8) as u8 & 0xF);
+                        self.inst(0x61, addr as u8);
+                    }
+                    None => {
+                        self.fixups
+                            .push((self.here, label.clone(), Fixup::UnpackHigh(nibble)));
+                        self.inst(0x60, 0);
+                        self.fixups.push((self.here, label, Fixup::UnpackLow));
+                        self.inst(0x61, 0);
+                    }
+                }
+            }
+            ":call" => self.addr_inst(0x2)?,
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+
+            ";" | "return" => self.inst(0x00, 0xEE),
+            "clear" => self.inst(0x00, 0xE0),
+            "hires" => self.inst(0x00, 0xFF),
+            "lores" => self.inst(0x00, 0xFE),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.inst(0x00, 0xC0 | n);
+            }
+            "scroll-right" => self.inst(0x00, 0xFB),
+            "scroll-left" => self.inst(0x00, 0xFC),
+            "exit" => self.inst(0x00, 0xFD),
+            "bcd" => self.reg_inst(0x33)?,
+            "save" => self.reg_inst(0x55)?,
+            "load" => self.reg_inst(0x65)?,
+            "saveflags" => self.reg_inst(0x75)?,
+            "loadflags" => self.reg_inst(0x85)?,
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.inst(0xD0 | x, y << 4 | n);
+            }
+            "jump" => self.addr_inst(0x1)?,
+            "jump0" => self.addr_inst(0xB)?,
+
+            "if" => self.if_statement(&token)?,
+            "else" => {
+                let (jump, start) = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| self.error(&token, "`else` without `begin`"))?;
+                let new_jump = self.here;
+                self.inst(0x10, 0x00);
+                self.patch(jump, self.here as u16);
+                self.branches.push((new_jump, start));
+            }
+            "end" => {
+                let (jump, _) = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| self.error(&token, "`end` without `begin`"))?;
+                self.patch(jump, self.here as u16);
+            }
+            "loop" => self.loops.push(Loop {
+                start: self.here,
+                token,
+                breaks: Vec::new(),
+            }),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error(&token, "`while` outside of a loop"));
+                }
+                self.conditional(true)?;
+                let jump = self.here;
+                self.inst(0x10, 0x00);
+                self.loops.last_mut().unwrap().breaks.push(jump);
+            }
+            "again" => {
+                let l = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| self.error(&token, "`again` without `loop`"))?;
+                self.inst(0x10 | (l.start >> 8) as u8, l.start as u8);
+                for jump in l.breaks {
+                    self.patch(jump, self.here as u16);
+                }
+            }
+
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let low = if token.text == "delay" { 0x15 } else { 0x18 };
+                self.inst(0xF0 | x, low);
+            }
+            "i" => self.i_statement()?,
+            text if self.register_of(text).is_some() => {
+                let x = self.register_of(text).unwrap();
+                self.assignment(x)?;
+            }
+            text if self.macros.contains_key(text) => self.call_macro(&token)?,
+            text if parse_number(text).is_some() => {
+                self.tokens.push_front(token);
+                let byte = self.byte()?;
+                self.emit(byte);
+            }
+            _ => {
+                // Any other name is a subroutine call
+                self.tokens.push_front(token);
+                self.addr_inst(0x2)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reg_inst(&mut self, low: u8) -> Result<(), AsmError> {
+        let x = self.register()?;
+        self.inst(0xF0 | x, low);
+        Ok(())
+    }
+
+    fn i_statement(&mut self) -> Result<(), AsmError> {
+        let op = self.next()?;
+        match op.text.as_str() {
+            ":=" => match self.peek(0) {
+                Some("hex") => {
+                    self.next()?;
+                    self.reg_inst(0x29)
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    self.reg_inst(0x30)
+                }
+                _ => self.addr_inst(0xA),
+            },
+            "+=" => self.reg_inst(0x1E),
+            _ => Err(self.error(&op, "Expected `:=` or `+=`")),
+        }
+    }
+
+    fn assignment(&mut self, x: u8) -> Result<(), AsmError> {
+        let op = self.next()?;
+        let rhs = self.next()?;
+        let y = self.register_of(&rhs.text);
+
+        let xy = |y: u8, low: u8| (0x80 | x, y << 4 | low);
+        let (a, b) = match (op.text.as_str(), rhs.text.as_str(), y) {
+            (":=", "random", _) => (0xC0 | x, self.byte()?),
+            (":=", "key", _) => (0xF0 | x, 0x0A),
+            (":=", "delay", _) => (0xF0 | x, 0x07),
+            (":=", _, Some(y)) => xy(y, 0x0),
+            ("|=", _, Some(y)) => xy(y, 0x1),
+            ("&=", _, Some(y)) => xy(y, 0x2),
+            ("^=", _, Some(y)) => xy(y, 0x3),
+            ("+=", _, Some(y)) => xy(y, 0x4),
+            ("-=", _, Some(y)) => xy(y, 0x5),
+            (">>=", _, Some(y)) => xy(y, 0x6),
+            ("=-", _, Some(y)) => xy(y, 0x7),
+            ("<<=", _, Some(y)) => xy(y, 0xE),
+            (":=", _, None) => (0x60 | x, self.byte_of(&rhs)?),
+            ("+=", _, None) => (0x70 | x, self.byte_of(&rhs)?),
+            ("-=", _, None) => (0x70 | x, self.byte_of(&rhs)?.wrapping_neg()),
+            ("|=" | "&=" | "^=" | ">>=" | "=-" | "<<=", _, None) => {
+                return Err(self.error(&rhs, "Expected a register"))
+            }
+            _ => return Err(self.error(&op, format!("Unknown operator `{}`", op.text))),
+        };
+        self.inst(a, b);
+        Ok(())
+    }
+
+    fn if_statement(&mut self, token: &Token) -> Result<(), AsmError> {
+        // `if vX key then` has no right hand side
+        let end = match self.peek(1) {
+            Some("key" | "-key") => 2,
+            _ => 3,
+        };
+        match self.peek(end) {
+            Some("then") => {
+                self.conditional(false)?;
+                self.next()?;
+            }
+            Some("begin") => {
+                self.conditional(true)?;
+                self.next()?;
+                self.branches.push((self.here, token.clone()));
+                self.inst(0x10, 0x00);
+            }
+            _ => return Err(self.error(token, "Expected `then` or `begin` after the condition")),
+        }
+        Ok(())
+    }
+
+    // Emits a skip over the next instruction when the condition is false, or when it's true if
+    // `negated`. <, >, <= and >= use vf.
+    fn conditional(&mut self, negated: bool) -> Result<(), AsmError> {
+        let x = self.register()?;
+        let op = self.next()?;
+        let inverse = negate(&op.text).ok_or_else(|| self.error(&op, "Expected a comparison"))?;
+        // The skip happens when this holds
+        let skip_if = if negated {
+            negate(inverse).unwrap()
+        } else {
+            inverse
+        };
+
+        let (a, b) = match skip_if {
+            "key" => (0xE0 | x, 0x9E),
+            "-key" => (0xE0 | x, 0xA1),
+            _ => {
+                let rhs = self.next()?;
+                match (skip_if, self.register_of(&rhs.text)) {
+                    ("==", Some(y)) => (0x50 | x, y << 4),
+                    ("!=", Some(y)) => (0x90 | x, y << 4),
+                    ("==", None) => (0x30 | x, self.byte_of(&rhs)?),
+                    ("!=", None) => (0x40 | x, self.byte_of(&rhs)?),
+                    (_, y) => {
+                        match y {
+                            Some(y) => self.inst(0x8F, y << 4),
+                            None => {
+                                let nn = self.byte_of(&rhs)?;
+                                self.inst(0x6F, nn);
+                            }
+                        }
+                        // vf -= vX sets vf when vX <= rhs, vf =- vX when vX >= rhs. Like Octo,
+                        // skip on vf == 1 or vf != 1 rather than on vf == 0
+                        let (sub, skip) = match skip_if {
+                            ">" => (0x5, 0x4F),
+                            "<=" => (0x5, 0x3F),
+                            "<" => (0x7, 0x4F),
+                            _ => (0x7, 0x3F),
+                        };
+                        self.inst(0x8F, x << 4 | sub);
+                        (skip, 0x01)
+                    }
+                }
+            }
+        };
+        self.inst(a, b);
+        Ok(())
+    }
+
+    // :macro name params { body }
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.next()?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    fn call_macro(&mut self, name: &Token) -> Result<(), AsmError> {
+        // A call that is the last token of a body still counts as nested in it
+        while self.expansions.last() > Some(&self.tokens.len()) {
+            self.expansions.pop();
+        }
+        if self.expansions.len() == MAX_MACRO_DEPTH {
+            return Err(self.error(name, "Macros are nested too deeply"));
+        }
+
+        let m = &self.macros[&name.text];
+        let (params, body) = (m.params.clone(), m.body.clone());
+        self.expanded_tokens += body.len().max(1);
+        if self.expanded_tokens > MAX_EXPANDED_TOKENS {
+            return Err(self.error(name, "Macros expand to too many tokens"));
+        }
+        let mut args = HashMap::new();
+        for param in params {
+            args.insert(param, self.next()?.text);
+        }
+        self.expansions.push(self.tokens.len());
+
+        for mut token in body.into_iter().rev() {
+            if let Some(arg) = args.get(&token.text) {
+                token.text = arg.clone();
+            }
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Assembly, Vec<AsmError>> {
+        let mut errors = Vec::new();
+        for (_, token) in &self.branches {
+            errors.push(self.error(token, "`begin` without `end`"));
+        }
+        for l in &self.loops {
+            errors.push(self.error(&l.token, "`loop` without `again`"));
+        }
+
+        match self.labels.get("main") {
+            Some(&main) if self.has_main => self.patch(PROGRAM_START, main),
+            Some(_) => {}
+            None => {
+                let token = Token {
+                    text: String::new(),
+                    line: 1,
+                    column: 1,
+                };
+                errors.push(self.error(&token, "The program has no `main` label"));
+            }
+        }
+
+        for (addr, token, fixup) in std::mem::take(&mut self.fixups) {
+            let Some(&target) = self.labels.get(&token.text) else {
+                errors.push(self.error(&token, format!("Unknown label `{}`", token.text)));
+                continue;
+            };
+            let index = addr - PROGRAM_START;
+            match fixup {
+                Fixup::Addr => self.patch(addr, target),
+                Fixup::UnpackHigh(nibble) => {
+                    self.rom[index + 1] = (nibble << 4) | (target >> 8) as u8 & 0xF
+                }
+                Fixup::UnpackLow => self.rom[index + 1] = target as u8,
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Assembly {
+            code: self.rom,
+            symbols: self.symbols,
+            listing: String::new(),
+        })
+    }
+}
+
+#[test]
+fn test_octo() {
+    let code = assemble_octo(
+        r#"
+        :const SPEED 3
+        :alias x v1
+        : main
+            clear
+            x := 0
+            loop
+                x += SPEED
+                if x == 12 then x := 0
+                if x != 6 begin
+                    v2 := key
+                else
+                    sprite x x 5
+                end
+                while x < 9
+                draw
+            again
+        : draw
+            i := shape
+            ;
+        : shape 0xF0 0x90 0xF0
+        "#,
+    );
+    assert_eq!(
+        code.unwrap(),
+        [
+            0x00, 0xE0, 0x61, 0x00, 0x71, 0x03, 0x41, 0x0C, 0x61, 0x00, 0x41, 0x06, 0x12, 0x12,
+            0xF2, 0x0A, 0x12, 0x14, 0xD1, 0x15, 0x6F, 0x09, 0x8F, 0x17, 0x4F, 0x01, 0x12, 0x20,
+            0x22, 0x20, 0x12, 0x04, 0xA2, 0x24, 0x00, 0xEE, 0xF0, 0x90, 0xF0
+        ]
+    );
+}
+
+// The expected bytes follow the code generation of Octo's compiler
+#[test]
+fn test_octo_reference() {
+    let code = assemble_octo(
+        r#"
+        : main
+            :next target v0 := 0
+            v1 := 7
+            if v1 > 3 then v2 := 1
+            if v1 < v2 then v2 := 2
+            if v1 >= 3 begin v2 := 3 end
+            if v1 <= v3 begin v2 := 4 else v2 := 5 end
+            i := bighex v1
+            jump0 target
+            loop
+                v1 += -1
+                while v1 > 2
+            again
+            if v1 >= v2 then v0 := 1
+            if v1 <= 9 then v0 := 2
+        "#,
+    );
+    assert_eq!(
+        code.unwrap(),
+        [
+            0x60, 0x00, 0x61, 0x07, 0x6F, 0x03, 0x8F, 0x15, 0x3F, 0x01, 0x62, 0x01, 0x8F, 0x20,
+            0x8F, 0x17, 0x3F, 0x01, 0x62, 0x02, 0x6F, 0x03, 0x8F, 0x17, 0x3F, 0x01, 0x12, 0x1E,
+            0x62, 0x03, 0x8F, 0x30, 0x8F, 0x15, 0x3F, 0x01, 0x12, 0x2A, 0x62, 0x04, 0x12, 0x2C,
+            0x62, 0x05, 0xF1, 0x30, 0xB2, 0x01, 0x71, 0xFF, 0x6F, 0x02, 0x8F, 0x15, 0x4F, 0x01,
+            0x12, 0x3C, 0x12, 0x30, 0x8F, 0x20, 0x8F, 0x17, 0x4F, 0x01, 0x60, 0x01, 0x6F, 0x09,
+            0x8F, 0x15, 0x4F, 0x01, 0x60, 0x02
+        ]
+    );
+}
+
+#[test]
+fn test_octo_main_jump() {
+    let code = assemble_octo(": f ; : main f v0 -= 1 v0 <<= v0 :unpack 0xA f");
+    assert_eq!(
+        code.unwrap(),
+        [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02, 0x70, 0xFF, 0x80, 0x0E, 0x60, 0xA2, 0x61, 0x02]
+    );
+}
+
+#[test]
+fn test_octo_macros() {
+    let code = assemble_octo(
+        ":macro twice reg { reg += 1 reg += 1 } : main twice v3 if v3 key then twice v4",
+    );
+    assert_eq!(
+        code.unwrap(),
+        [0x73, 0x01, 0x73, 0x01, 0xE3, 0xA1, 0x74, 0x01, 0x74, 0x01]
+    );
+
+    // Only nesting is limited, not how many times macros are used
+    let source = format!(":macro inc {{ v0 += 1 }} : main {}", "inc ".repeat(20000));
+    assert_eq!(assemble_octo(&source).unwrap().len(), 40000);
+    let source = ":macro inc { v0 += 1 } :macro twice { inc inc } : main twice twice";
+    assert_eq!(assemble_octo(source).unwrap(), [0x70, 0x01].repeat(4));
+
+    let errors = assemble_octo(":macro forever { v0 += 1 forever } : main forever").unwrap_err();
+    assert_eq!(errors[0].message, "Macros are nested too deeply");
+
+    // Each macro uses the previous one twice, 2^40 uses in total
+    let mut source = ":macro m0 { }".to_string();
+    for i in 1..40 {
+        source += &format!(" :macro m{} {{ m{} m{} }}", i, i - 1, i - 1);
+    }
+    let errors = assemble_octo(&(source + " : main m39")).unwrap_err();
+    assert_eq!(errors[0].message, "Macros expand to too many tokens");
+}
+
+#[test]
+fn test_octo_symbols() {
+    let assembly = assemble_octo_named("<input>", ": main : start : title clear : done").unwrap();
+    assert_eq!(
+        assembly.symbols.to_string(),
+        "0200 main\n0200 start\n0200 title\n0202 done\n"
+    );
+}
+
+#[test]
+fn test_octo_errors() {
+    let errors = assemble_octo(": start jump nowhere loop").unwrap_err();
+    let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        [
+            "`loop` without `again`",
+            "The program has no `main` label",
+            "Unknown label `nowhere`"
+        ]
+    );
+
+    let errors = assemble_octo("").unwrap_err();
+    assert_eq!(errors[0].message, "The program has no `main` label");
+
+    let errors = assemble_octo(": main :org 0xFFFE clear 1").unwrap_err();
+    assert_eq!(
+        errors[0].message,
+        "`1` goes past the end of memory at 0x10000"
+    );
+
+    let errors = assemble_octo(": main :org 0x100 clear").unwrap_err();
+    assert_eq!(
+        errors[0].message,
+        "256 is out of range, expected 512 to 65535"
+    );
+}
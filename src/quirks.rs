@@ -0,0 +1,99 @@
+use crate::memory;
+
+/// Behaviour differences between CHIP-8 interpreters. ROMs are usually written against one of
+/// them, so picking the wrong set can make a game run incorrectly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quirks {
+    /// 8xy6 and 8xyE shift vY into vX, instead of shifting vX in place.
+    pub shift_uses_vy: bool,
+    /// 8xy1, 8xy2 and 8xy3 set vF to 0.
+    pub vf_reset: bool,
+    /// Fx55 and Fx65 leave I pointing after the last register stored or loaded.
+    pub memory_increments_i: bool,
+    /// Bnnn jumps to xnn + vX instead of nnn + v0.
+    pub jump_uses_vx: bool,
+    /// Sprites drawn past the edge of the screen wrap around instead of being clipped.
+    pub sprite_wrap: bool,
+    /// Dxyn waits for the next frame before the next instruction runs.
+    pub display_wait: bool,
+    /// Fx1E sets vF to 1 when I goes past 0xFFF, and to 0 otherwise.
+    pub i_overflow_vf: bool,
+    /// The program can address 64 KiB of memory instead of 4 KiB.
+    pub memory_64k: bool,
+}
+
+pub const PRESETS: &[&str] = &["vip", "chip48", "schip", "xochip"];
+
+impl Quirks {
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            vf_reset: true,
+            memory_increments_i: true,
+            jump_uses_vx: false,
+            sprite_wrap: false,
+            display_wait: true,
+            i_overflow_vf: false,
+            memory_64k: false,
+        }
+    }
+
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            vf_reset: false,
+            memory_increments_i: false,
+            jump_uses_vx: true,
+            sprite_wrap: true,
+            display_wait: false,
+            i_overflow_vf: false,
+            memory_64k: false,
+        }
+    }
+
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            vf_reset: false,
+            memory_increments_i: false,
+            jump_uses_vx: true,
+            sprite_wrap: false,
+            display_wait: false,
+            i_overflow_vf: false,
+            memory_64k: false,
+        }
+    }
+
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            vf_reset: false,
+            memory_increments_i: true,
+            jump_uses_vx: false,
+            sprite_wrap: true,
+            display_wait: false,
+            i_overflow_vf: false,
+            memory_64k: true,
+        }
+    }
+
+    /// How many bytes of memory the program can address.
+    pub fn memory_size(&self) -> usize {
+        if self.memory_64k {
+            memory::MEMORY_SIZE
+        } else {
+            memory::CHIP8_MEMORY_SIZE
+        }
+    }
+
+    /// Looks up a preset by the names in `PRESETS`.
+    pub fn from_preset(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::superchip()),
+            "xochip" => Some(Quirks::xochip()),
+            _ => None,
+        }
+    }
+}
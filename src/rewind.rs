@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+
+/// Five minutes of frames at 60 Hz.
+pub const DEFAULT_REWIND_FRAMES: usize = 5 * 60 * 60;
+
+// Differing bytes closer than this are stored as one run, a run costs a few bytes of header
+const MIN_GAP: usize = 8;
+
+/// The states of the last frames, from `Chip8::save_state`, to go back in time one frame at a
+/// time. Only the newest state is kept whole, every older one is stored as the bytes that
+/// differ from the state after it, which is little from one frame to the next.
+pub struct Rewind {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    // Oldest first. Each one turns the state after it back into its own.
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Keeps at most `capacity` states.
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity: capacity.max(1),
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            self.deltas.push_back(delta(&state, &previous));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.newest = Some(state);
+    }
+
+    /// Drops the newest state and returns the one before it, which becomes the newest.
+    pub fn back(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let newest = self.newest.as_mut().unwrap();
+        apply(newest, &delta);
+        Some(newest)
+    }
+
+    /// The number of states kept.
+    pub fn len(&self) -> usize {
+        self.newest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// The bytes used by the states.
+    pub fn size(&self) -> usize {
+        let newest = self.newest.as_ref().map_or(0, |state| state.len());
+        newest + self.deltas.iter().map(|delta| delta.len()).sum::<usize>()
+    }
+}
+
+// The runs of `to` that differ from `from`, each as the distance from the end of the previous
+// run, the length and the bytes. A state of another length is stored whole, after a 0 length.
+fn delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    if from.len() != to.len() {
+        write_varint(&mut delta, 0);
+        write_varint(&mut delta, 0);
+        delta.extend(to);
+        return delta;
+    }
+
+    let mut end = 0;
+    let mut i = 0;
+    while i < to.len() {
+        if from[i] == to[i] {
+            i += 1;
+            continue;
+        }
+        // The run goes on until MIN_GAP equal bytes in a row
+        let start = i;
+        let mut last = i;
+        while i < to.len() && i - last <= MIN_GAP {
+            if from[i] != to[i] {
+                last = i;
+            }
+            i += 1;
+        }
+        write_varint(&mut delta, start - end);
+        write_varint(&mut delta, last + 1 - start);
+        delta.extend(&to[start..=last]);
+        end = last + 1;
+    }
+    delta
+}
+
+fn apply(state: &mut Vec<u8>, mut delta: &[u8]) {
+    let mut end = 0;
+    while !delta.is_empty() {
+        let skip = read_varint(&mut delta);
+        let len = read_varint(&mut delta);
+        if len == 0 {
+            *state = delta.to_vec();
+            return;
+        }
+        let start = end + skip;
+        state[start..start + len].copy_from_slice(&delta[..len]);
+        delta = &delta[len..];
+        end = start + len;
+    }
+}
+
+// LEB128, 7 bits per byte with the high bit set on all but the last
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some((&byte, rest)) = bytes.split_first() {
+        *bytes = rest;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+#[test]
+fn test_rewind() {
+    let mut states: Vec<Vec<u8>> = Vec::new();
+    let mut state = vec![0; 4096];
+    for frame in 0..10 {
+        state[frame * 100] = frame as u8 + 1;
+        state[frame * 100 + 20] = 0xFF;
+        state[4095] = frame as u8;
+        states.push(state.clone());
+    }
+
+    let mut rewind = Rewind::new(8);
+    for state in &states {
+        rewind.push(state.clone());
+    }
+    assert_eq!(rewind.len(), 8);
+    // Small changes take a few bytes per frame
+    assert!(rewind.size() < 4096 + 7 * 32, "{} bytes", rewind.size());
+
+    for frame in (2..9).rev() {
+        assert_eq!(rewind.back(), Some(states[frame].as_slice()));
+    }
+    assert_eq!(rewind.back(), None);
+    assert_eq!(rewind.len(), 1);
+
+    // Going on from an older frame forgets the newer ones
+    rewind.push(vec![1; 10]);
+    assert_eq!(rewind.back(), Some(states[2].as_slice()));
+}
@@ -1,106 +1,189 @@
-use std::io::Write;
-use std::{
-    io::stdout,
-    ops::{Index, IndexMut},
-};
-use termion::{clear, cursor, raw::IntoRawMode};
+use std::ops::{Index, IndexMut};
 
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+// Pixels hold one bit per XO-CHIP bitplane, giving four colours.
+#[derive(Clone)]
 pub struct Screen {
-    current: [[bool; 32]; 64],
-    previous: [[bool; 32]; 64],
-    redraw: bool,
-    last_draw: std::time::Instant,
-    stdout: Option<termion::raw::RawTerminal<std::io::Stdout>>,
-    size: (u16, u16),
-    full_redraw: bool,
+    pixels: [[u8; HIRES_HEIGHT]; HIRES_WIDTH],
+    planes: u8,
+    hires: bool,
+    dirty: bool,
 }
 
 impl Screen {
-    pub fn new(raw: bool) -> Screen {
-        let stdout = if raw {
-            let mut stdout = stdout().into_raw_mode().unwrap();
-            write!(stdout, "{}", clear::All).unwrap();
-            Some(stdout)
-        } else {
-            None
-        };
+    pub fn new() -> Screen {
         Screen {
-            current: [[false; 32]; 64],
-            previous: [[false; 32]; 64],
-            redraw: false,
-            last_draw: std::time::Instant::now(),
-            stdout,
-            size: termion::terminal_size().unwrap(),
-            full_redraw: true,
+            pixels: [[0; HIRES_HEIGHT]; HIRES_WIDTH],
+            planes: 1,
+            hires: false,
+            dirty: true,
         }
     }
 
-    pub fn draw(&mut self) {
-        if self.stdout.is_none() {
-            return;
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            LORES_WIDTH
         }
-        let stdout = self.stdout.as_mut().unwrap();
-        write!(
-            stdout,
-            "{}{} x {}",
-            cursor::Goto(1, 33),
-            self.size.0,
-            self.size.1
-        )
-        .unwrap();
-
-        if self.last_draw.elapsed().as_millis() < 1000 / 60 {
-            return;
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            LORES_HEIGHT
         }
-        self.last_draw = std::time::Instant::now();
-        if !self.redraw {
-            return;
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // Switching resolution clears every plane.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        for column in self.pixels.iter_mut() {
+            column.fill(0);
         }
-        for y in 0..32 {
-            for x in 0..64 {
-                if self.current[x][y] != self.previous[x][y] || self.full_redraw {
-                    write!(
-                        stdout,
-                        "{}{}",
-                        cursor::Goto((x * 2 + 1) as u16, (y + 1) as u16),
-                        if self.current[x][y] { "██" } else { "  " }
-                    )
-                    .unwrap();
-                    self.previous[x][y] = self.current[x][y];
-                }
+        self.dirty = true;
+    }
+
+    /// Bitmask of the planes that drawing, clearing and scrolling apply to.
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0b11;
+    }
+
+    pub fn clear(&mut self) {
+        for x in 0..HIRES_WIDTH {
+            for y in 0..HIRES_HEIGHT {
+                self.pixels[x][y] &= !self.planes;
             }
         }
-        stdout.flush().unwrap();
+        self.dirty = true;
+    }
 
-        if let Ok(size) = termion::terminal_size() {
-            if size != self.size {
-                self.size = size;
-                self.full_redraw = true;
-                self.redraw = true;
+    pub fn scroll_down(&mut self, n: usize) {
+        let height = self.height();
+        for x in 0..self.width() {
+            for y in (0..height).rev() {
+                let moved = if y >= n { self.pixels[x][y - n] } else { 0 };
+                self.set_planes(x, y, moved);
             }
         }
+        self.dirty = true;
     }
 
-    pub fn clear(&mut self) {
-        for x in 0..64 {
-            for y in 0..32 {
-                self.current[x][y] = false;
+    pub fn scroll_left(&mut self, n: usize) {
+        let width = self.width();
+        for x in 0..width {
+            for y in 0..self.height() {
+                let moved = if x + n < width { self.pixels[x + n][y] } else { 0 };
+                self.set_planes(x, y, moved);
+            }
+        }
+        self.dirty = true;
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        for x in (0..self.width()).rev() {
+            for y in 0..self.height() {
+                let moved = if x >= n { self.pixels[x - n][y] } else { 0 };
+                self.set_planes(x, y, moved);
             }
         }
+        self.dirty = true;
+    }
+
+    // Replaces the selected planes of a pixel, leaving the others untouched
+    fn set_planes(&mut self, x: usize, y: usize, value: u8) {
+        self.pixels[x][y] = (self.pixels[x][y] & !self.planes) | (value & self.planes);
+    }
+
+    /// Colour index of a pixel, bit 0 is the first plane and bit 1 the second.
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[x][y]
+    }
+
+    /// The pixels that differ from `before`, with their value there, as `x * HIRES_HEIGHT + y`.
+    pub(crate) fn changes_since(&self, before: &Screen) -> Vec<(u16, u8)> {
+        let mut changes = Vec::new();
+        for (x, (column, old_column)) in self.pixels.iter().zip(&before.pixels).enumerate() {
+            for (y, (pixel, old)) in column.iter().zip(old_column).enumerate() {
+                if pixel != old {
+                    changes.push(((x * HIRES_HEIGHT + y) as u16, *old));
+                }
+            }
+        }
+        changes
+    }
+
+    /// Puts back what `changes_since` returned.
+    pub(crate) fn undo(&mut self, changes: &[(u16, u8)], planes: u8, hires: bool) {
+        for &(index, old) in changes {
+            let index = index as usize;
+            self.pixels[index / HIRES_HEIGHT][index % HIRES_HEIGHT] = old;
+        }
+        self.planes = planes;
+        self.hires = hires;
+        self.dirty = true;
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.planes);
+        state.u8(self.hires as u8);
+        for column in &self.pixels {
+            state.bytes(column);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.planes = state.u8()?;
+        self.hires = state.bool()?;
+        for column in self.pixels.iter_mut() {
+            *column = state.array()?;
+        }
+        if self.planes > 0b11 || self.pixels.iter().flatten().any(|p| *p > 0b11) {
+            return Err(StateError::Invalid);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    // Returns whether the framebuffer changed since the last call, so frontends
+    // can skip redrawing identical frames.
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.dirty, false)
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen::new()
     }
 }
 
 impl Index<usize> for Screen {
-    type Output = [bool; 32];
+    type Output = [u8; HIRES_HEIGHT];
 
-    fn index(&self, index: usize) -> &[bool; 32] {
-        &self.current[index]
+    fn index(&self, index: usize) -> &[u8; HIRES_HEIGHT] {
+        &self.pixels[index]
     }
 }
 
 impl IndexMut<usize> for Screen {
-    fn index_mut(&mut self, index: usize) -> &mut [bool; 32] {
-        self.redraw = true;
-        &mut self.current[index]
+    fn index_mut(&mut self, index: usize) -> &mut [u8; HIRES_HEIGHT] {
+        self.dirty = true;
+        &mut self.pixels[index]
     }
 }
@@ -0,0 +1,133 @@
+use std::fmt;
+
+// Save state files start with the magic and the version of the layout that follows. Numbers
+// are big-endian.
+const MAGIC: &[u8; 4] = b"C8ST";
+pub const STATE_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    Truncated,
+    /// A value that no machine can be in, like a stack pointer past the stack.
+    Invalid,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Save state version {} is not supported, expected {}",
+                    version, STATE_VERSION
+                )
+            }
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid => write!(f, "Save state holds invalid values"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(STATE_VERSION.to_be_bytes());
+        StateWriter { bytes }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend(value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend(value.to_be_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the magic and the version.
+    pub fn new(bytes: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        let Some(rest) = bytes.strip_prefix(MAGIC) else {
+            return Err(StateError::NotAState);
+        };
+        let mut reader = StateReader { bytes: rest };
+        match reader.u16()? {
+            STATE_VERSION => Ok(reader),
+            version => Err(StateError::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid),
+        }
+    }
+
+    /// Fails if anything is left after the state.
+    pub fn finish(self) -> Result<(), StateError> {
+        match self.bytes {
+            [] => Ok(()),
+            _ => Err(StateError::Invalid),
+        }
+    }
+}
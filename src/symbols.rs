@@ -0,0 +1,117 @@
+use std::{fmt, str::FromStr};
+
+/// Names of addresses in a ROM. The assembler writes them to a symbol file, one `addr name`
+/// per line, so the emulator can show labels instead of bare addresses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    // Sorted by address
+    symbols: Vec<(u16, String)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        let index = self.symbols.partition_point(|(a, _)| *a <= addr);
+        self.symbols.insert(index, (addr, name.to_string()));
+    }
+
+    /// The first name given to `addr`.
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        let index = self.symbols.partition_point(|(a, _)| *a < addr);
+        match self.symbols.get(index) {
+            Some((a, name)) if *a == addr => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn addr(&self, name: &str) -> Option<u16> {
+        self.symbols
+            .iter()
+            .find(|(_, n)| n == name)
+            .map(|(a, _)| *a)
+    }
+
+    /// `addr` relative to the closest name before it, like `loop+4`.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let index = self.symbols.partition_point(|(a, _)| *a <= addr);
+        let (base, _) = self.symbols.get(index.checked_sub(1)?)?;
+        let name = self.name(*base)?;
+        match addr - base {
+            0 => Some(name.to_string()),
+            offset => Some(format!("{}+{}", name, offset)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.symbols
+            .iter()
+            .map(|(addr, name)| (*addr, name.as_str()))
+    }
+}
+
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (addr, name) in self.iter() {
+            writeln!(f, "{:04x} {}", addr, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of a symbol file that isn't `addr name`, 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSymbolLine(pub usize);
+
+impl fmt::Display for InvalidSymbolLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid symbol at line {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidSymbolLine {}
+
+impl FromStr for SymbolTable {
+    type Err = InvalidSymbolLine;
+
+    fn from_str(text: &str) -> Result<SymbolTable, InvalidSymbolLine> {
+        let mut table = SymbolTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (addr, name) = line
+                .split_once(' ')
+                .and_then(|(addr, name)| Some((u16::from_str_radix(addr, 16).ok()?, name.trim())))
+                .ok_or(InvalidSymbolLine(i + 1))?;
+            table.insert(addr, name);
+        }
+        Ok(table)
+    }
+}
+
+#[test]
+fn test_symbol_table() {
+    let mut table = SymbolTable::new();
+    table.insert(0x210, "loop");
+    table.insert(0x200, "start");
+    table.insert(0x200, "main");
+
+    assert_eq!(table.name(0x200), Some("start"));
+    assert_eq!(table.addr("main"), Some(0x200));
+    assert_eq!(table.describe(0x214).as_deref(), Some("loop+4"));
+    assert_eq!(table.describe(0x100), None);
+
+    let text = table.to_string();
+    assert_eq!(text, "0200 start\n0200 main\n0210 loop\n");
+    assert_eq!(text.parse(), Ok(table));
+    assert_eq!(
+        "0200 start\nnope\n".parse::<SymbolTable>(),
+        Err(InvalidSymbolLine(2))
+    );
+}
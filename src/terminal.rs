@@ -0,0 +1,244 @@
+use std::io::{stdout, Stdout, Write};
+
+use chip8emu::{
+    keyboard::Keyboard,
+    screen::{Screen, HIRES_WIDTH, LORES_HEIGHT},
+    FRAME_RATE,
+};
+use termion::{
+    clear,
+    color::{Bg, Fg, Rgb},
+    cursor,
+    input::{Keys, TermRead},
+    raw::{IntoRawMode, RawTerminal},
+    style, AsyncReader,
+};
+
+// Every terminal cell shows two vertically stacked pixels in high resolution, and half of a
+// pixel in low resolution, so both modes take up the same 128x32 cells.
+const COLUMNS: usize = HIRES_WIDTH;
+const ROWS: usize = LORES_HEIGHT;
+
+// Colours for each combination of the two XO-CHIP planes
+const PALETTE: [Rgb; 4] = [
+    Rgb(0, 0, 0),
+    Rgb(255, 255, 255),
+    Rgb(170, 170, 170),
+    Rgb(85, 85, 85),
+];
+
+pub struct Display {
+    stdout: RawTerminal<Stdout>,
+    previous: [[u8; ROWS]; COLUMNS],
+    last_draw: std::time::Instant,
+    size: (u16, u16),
+    full_redraw: bool,
+}
+
+impl Display {
+    pub fn new() -> Display {
+        let mut stdout = stdout().into_raw_mode().unwrap();
+        write!(stdout, "{}", clear::All).unwrap();
+        Display {
+            stdout,
+            previous: [[0; ROWS]; COLUMNS],
+            last_draw: std::time::Instant::now(),
+            size: termion::terminal_size().unwrap(),
+            full_redraw: true,
+        }
+    }
+
+    /// Leaves raw mode and moves the cursor below the screen, so regular output can follow.
+    pub fn suspend(&mut self) {
+        write!(self.stdout, "{}", cursor::Goto(1, ROWS as u16 + 3)).unwrap();
+        self.stdout.flush().unwrap();
+        self.stdout.suspend_raw_mode().unwrap();
+    }
+
+    pub fn draw(&mut self, screen: &mut Screen) {
+        write!(
+            self.stdout,
+            "{}{} x {}",
+            cursor::Goto(1, ROWS as u16 + 1),
+            self.size.0,
+            self.size.1
+        )
+        .unwrap();
+
+        if self.last_draw.elapsed().as_millis() < 1000 / 60 {
+            return;
+        }
+        self.last_draw = std::time::Instant::now();
+        if !screen.take_dirty() && !self.full_redraw {
+            return;
+        }
+        for y in 0..ROWS {
+            for x in 0..COLUMNS {
+                let cell = cell_at(screen, x, y);
+                if cell != self.previous[x][y] || self.full_redraw {
+                    write!(
+                        self.stdout,
+                        "{}{}{}▀{}",
+                        cursor::Goto((x + 1) as u16, (y + 1) as u16),
+                        Fg(PALETTE[(cell & 0b11) as usize]),
+                        Bg(PALETTE[(cell >> 2) as usize]),
+                        style::Reset
+                    )
+                    .unwrap();
+                    self.previous[x][y] = cell;
+                }
+            }
+        }
+        self.full_redraw = false;
+        self.stdout.flush().unwrap();
+
+        if let Ok(size) = termion::terminal_size() {
+            if size != self.size {
+                self.size = size;
+                self.full_redraw = true;
+            }
+        }
+    }
+
+    /// Shows a line of text below the screen.
+    pub fn draw_status(&mut self, text: &str) {
+        write!(
+            self.stdout,
+            "{}{}{}",
+            cursor::Goto(1, ROWS as u16 + 2),
+            text,
+            clear::UntilNewline
+        )
+        .unwrap();
+        self.stdout.flush().unwrap();
+    }
+
+    /// Shows `lines` to the right of the screen, clearing the rest of the rows there.
+    pub fn draw_pane(&mut self, lines: &[String]) {
+        let x = COLUMNS as u16 + 3;
+        for y in 0..ROWS.max(lines.len()) {
+            let line = lines.get(y).map_or("", |line| line.as_str());
+            write!(
+                self.stdout,
+                "{}{}{}",
+                cursor::Goto(x, y as u16 + 1),
+                line,
+                clear::UntilNewline
+            )
+            .unwrap();
+        }
+        self.stdout.flush().unwrap();
+    }
+}
+
+// Bits 0-1 are the colour of the upper half of the cell, bits 2-3 the lower half.
+fn cell_at(screen: &Screen, x: usize, y: usize) -> u8 {
+    if screen.is_hires() {
+        screen[x][y * 2] | screen[x][y * 2 + 1] << 2
+    } else {
+        screen[x / 2][y] | screen[x / 2][y] << 2
+    }
+}
+
+/// Keys that control the emulator rather than the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    /// Save state slots are 1 to 4, F1 to F4 save and F5 to F8 load.
+    Save(u8),
+    Load(u8),
+    /// Goes back a number of frames, F9 one and F10 a second.
+    Rewind(usize),
+}
+
+/// What was typed on the debugger command line since the last read.
+pub enum LineInput {
+    Editing,
+    Entered(String),
+    Quit,
+}
+
+pub struct Input {
+    keys_iter: Keys<AsyncReader>,
+    pause_requested: bool,
+    hotkey: Option<Hotkey>,
+    line: String,
+}
+
+impl Input {
+    pub fn new() -> Input {
+        Input {
+            keys_iter: termion::async_stdin().keys(),
+            pause_requested: false,
+            hotkey: None,
+            line: String::new(),
+        }
+    }
+
+    /// Whether Esc was pressed since the last call, to pause the debugger.
+    pub fn take_pause_request(&mut self) -> bool {
+        std::mem::take(&mut self.pause_requested)
+    }
+
+    /// The last hotkey pressed since the last call.
+    pub fn take_hotkey(&mut self) -> Option<Hotkey> {
+        self.hotkey.take()
+    }
+
+    /// The debugger command line being typed.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Edits the debugger command line with the keys typed since the last read.
+    pub fn read_line(&mut self) -> LineInput {
+        for key in self.keys_iter.by_ref() {
+            match key.unwrap() {
+                termion::event::Key::Char('\n') => {
+                    return LineInput::Entered(std::mem::take(&mut self.line))
+                }
+                termion::event::Key::Char(c) => self.line.push(c),
+                termion::event::Key::Backspace => {
+                    self.line.pop();
+                }
+                termion::event::Key::Ctrl('c') => return LineInput::Quit,
+                _ => {}
+            }
+        }
+        LineInput::Editing
+    }
+
+    /// Presses the keys typed since the last update. Returns false once the user asks to quit.
+    pub fn update(&mut self, keyboard: &mut Keyboard) -> bool {
+        for key in self.keys_iter.by_ref() {
+            match key.unwrap() {
+                termion::event::Key::Char('1') => keyboard.press(0x1),
+                termion::event::Key::Char('2') => keyboard.press(0x2),
+                termion::event::Key::Char('3') => keyboard.press(0x3),
+                termion::event::Key::Char('4') => keyboard.press(0xC),
+                termion::event::Key::Char('q') => keyboard.press(0x4),
+                termion::event::Key::Char('w') => keyboard.press(0x5),
+                termion::event::Key::Char('e') => keyboard.press(0x6),
+                termion::event::Key::Char('r') => keyboard.press(0xD),
+                termion::event::Key::Char('a') => keyboard.press(0x7),
+                termion::event::Key::Char('s') => keyboard.press(0x8),
+                termion::event::Key::Char('d') => keyboard.press(0x9),
+                termion::event::Key::Char('f') => keyboard.press(0xE),
+                termion::event::Key::Char('z') => keyboard.press(0xA),
+                termion::event::Key::Char('x') => keyboard.press(0x0),
+                termion::event::Key::Char('c') => keyboard.press(0xB),
+                termion::event::Key::Char('v') => keyboard.press(0xF),
+
+                termion::event::Key::Esc => self.pause_requested = true,
+                termion::event::Key::F(n @ 1..=4) => self.hotkey = Some(Hotkey::Save(n)),
+                termion::event::Key::F(n @ 5..=8) => self.hotkey = Some(Hotkey::Load(n - 4)),
+                termion::event::Key::F(9) => self.hotkey = Some(Hotkey::Rewind(1)),
+                termion::event::Key::F(10) => {
+                    self.hotkey = Some(Hotkey::Rewind(FRAME_RATE as usize))
+                }
+                termion::event::Key::Ctrl('c') => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+}
@@ -1,54 +1,133 @@
-use std::time::{Duration, Instant};
+use crate::state::{StateError, StateReader, StateWriter};
 
-use rodio::{OutputStream, Source};
+pub const DEFAULT_PITCH: u8 = 64;
 
+#[derive(Clone)]
 pub struct Timers {
     pub delay: u8,
     pub sound: u8,
-    _stream: rodio::OutputStream,
-    sink: rodio::Sink,
-    last_decrement: Instant,
-    playing: bool,
+    /// XO-CHIP 1-bit audio samples, played instead of the default beep once loaded.
+    pub pattern: Option<[u8; 16]>,
+    pub pitch: u8,
+    // Instructions run since the timers last decremented. Counting instructions rather than
+    // time makes a run depend only on the program and its input.
+    instructions: u64,
 }
 
 impl Timers {
     pub fn new() -> Timers {
-        let (stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink = rodio::Sink::try_new(&stream_handle).unwrap();
         Timers {
             delay: 0,
             sound: 0,
-            last_decrement: Instant::now(),
-            _stream: stream,
-            sink,
-            playing: false,
+            pattern: None,
+            pitch: DEFAULT_PITCH,
+            instructions: 0,
         }
     }
 
-    pub fn update(&mut self) {
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last_decrement);
-        if elapsed.as_secs() < 1 {
-            return;
+    /// Counts an instruction, ticking every `instructions_per_tick` of them.
+    pub fn update(&mut self, instructions_per_tick: u64) {
+        self.instructions += 1;
+        if self.instructions >= instructions_per_tick {
+            self.instructions = 0;
+            self.tick();
         }
-        self.last_decrement -= Duration::from_millis(1000 / 60);
+    }
+
+    /// Instructions run since the timers last decremented, which is how far into its frame the
+    /// program is.
+    pub fn frame_instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Ticks for a frame that ended before its last instruction, like one cut short by the
+    /// display wait quirk, so every frame ticks once.
+    pub fn end_frame(&mut self) {
+        if self.instructions > 0 {
+            self.instructions = 0;
+            self.tick();
+        }
+    }
+
+    /// Decrements the timers that aren't 0 yet, which happens 60 times per second.
+    pub fn tick(&mut self) {
         if self.delay > 0 {
             self.delay -= 1;
         }
         if self.sound > 0 {
             self.sound -= 1;
         }
+    }
 
-        if self.sound > 0 && (!self.playing || self.sink.empty()) {
-            let source = rodio::source::SineWave::new(440.0)
-                .amplify(0.2)
-                .take_duration(std::time::Duration::from_millis(1000 / 60 * self.sound as u64));
-            self.sink.append(source);
-            self.playing = true;
-        }
-        if self.sound == 0 && self.playing {
-            self.playing = false;
-            self.sink.stop();
-        }
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.delay);
+        state.u8(self.sound);
+        state.u8(self.pitch);
+        state.u8(self.pattern.is_some() as u8);
+        state.bytes(&self.pattern.unwrap_or_default());
+        state.u64(self.instructions);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.delay = state.u8()?;
+        self.sound = state.u8()?;
+        self.pitch = state.u8()?;
+        let has_pattern = state.bool()?;
+        let pattern = state.array()?;
+        self.pattern = has_pattern.then_some(pattern);
+        self.instructions = state.u64()?;
+        Ok(())
+    }
+
+    /// Rate in Hz at which the audio pattern bits are played.
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Timers::new()
+    }
+}
+
+#[test]
+fn test_update() {
+    let mut timers = Timers::new();
+    timers.delay = 2;
+    timers.sound = 1;
+
+    for _ in 0..9 {
+        timers.update(10);
+    }
+    assert_eq!((timers.delay, timers.sound), (2, 1));
+    timers.update(10);
+    assert_eq!((timers.delay, timers.sound), (1, 0));
+    for _ in 0..20 {
+        timers.update(10);
+    }
+    assert_eq!((timers.delay, timers.sound), (0, 0));
+}
+
+#[test]
+fn test_end_frame() {
+    let mut timers = Timers::new();
+    timers.delay = 3;
+
+    // A whole frame already ticked on its last instruction
+    for _ in 0..10 {
+        timers.update(10);
+    }
+    timers.end_frame();
+    assert_eq!(timers.delay, 2);
+    // A short one ticks at its end, and the next frame starts over
+    for _ in 0..4 {
+        timers.update(10);
+    }
+    timers.end_frame();
+    assert_eq!(timers.delay, 1);
+    for _ in 0..9 {
+        timers.update(10);
     }
+    assert_eq!(timers.delay, 1);
 }
@@ -0,0 +1,64 @@
+use std::collections::VecDeque;
+
+use crate::{cpu::Cpu, keyboard::Keyboard, timers::Timers};
+
+/// About two minutes at the default clock speed.
+pub const DEFAULT_UNDO_STEPS: usize = 100_000;
+
+/// What an instruction changed, to put the machine back as it was before it ran.
+pub(crate) struct UndoRecord {
+    pub cpu: Cpu,
+    pub timers: Timers,
+    /// Ex9E and ExA1 release the key they check.
+    pub keyboard: Keyboard,
+    /// Old values of the bytes written, in the order they were written.
+    pub memory: Vec<(usize, u8)>,
+    /// Old values of the pixels changed, as `x * HIRES_HEIGHT + y`.
+    pub pixels: Vec<(u16, u8)>,
+    pub planes: u8,
+    pub hires: bool,
+}
+
+/// The instructions run last, newest last, so `Chip8::undo` can run them backwards.
+pub struct UndoLog {
+    capacity: usize,
+    records: VecDeque<UndoRecord>,
+}
+
+impl UndoLog {
+    /// Keeps the changes of at most `capacity` instructions.
+    pub fn new(capacity: usize) -> UndoLog {
+        UndoLog {
+            capacity,
+            records: VecDeque::new(),
+        }
+    }
+
+    /// The number of instructions that can be undone.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Forgets every instruction, for when the machine changed some other way, like loading a
+    /// state.
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub(crate) fn push(&mut self, record: UndoRecord) {
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        if self.capacity > 0 {
+            self.records.push_back(record);
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+}